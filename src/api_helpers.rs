@@ -1,41 +1,303 @@
-use actix_web::dev::Body;
-use actix_web::http::header::CONTENT_TYPE;
+use crate::utils::json_path;
+use actix_web::dev::{Body, Payload};
+use actix_web::http::header::{CONTENT_TYPE, RETRY_AFTER};
 use actix_web::http::HeaderValue;
 use actix_web::http::StatusCode;
-use actix_web::HttpResponse;
+use actix_web::web::{Bytes, Json};
 use actix_web::ResponseError;
 use actix_web::Result as ActixResult;
-use actix_web::web::Json;
+use actix_web::{FromRequest, HttpMessage, HttpRequest, HttpResponse};
 use derive_more::{Display, Error};
-use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use validator::{ValidationError, ValidationErrors, ValidationErrorsKind};
 
 pub type ApiResult<T> = ActixResult<Json<T>>;
 
+/// Stable, machine-readable error codes. Clients should rely on these instead of `message`.
+#[derive(Debug, Serialize, Deserialize, Display, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiErrorCode {
+    /// Unexpected failure of godbox itself
+    #[display(fmt = "internal_error")]
+    InternalError,
+    /// The request body is malformed, or a field is invalid
+    #[display(fmt = "invalid_request")]
+    InvalidRequest,
+    /// The request body has one or more violations, listed in `violations`
+    #[display(fmt = "validation_failed")]
+    ValidationFailed,
+    /// `files` is not valid base64
+    #[display(fmt = "invalid_files_encoding")]
+    InvalidFilesEncoding,
+    /// `files` is not a valid zip archive
+    #[display(fmt = "invalid_archive")]
+    InvalidArchive,
+    /// A setting is over the maximum allowed by the administrator
+    #[display(fmt = "limit_exceeded")]
+    LimitExceeded,
+    #[display(fmt = "profiling_disabled")]
+    ProfilingDisabled,
+    #[display(fmt = "network_disabled")]
+    NetworkDisabled,
+    #[display(fmt = "cg_timing_toggle_disabled")]
+    CgTimingToggleDisabled,
+    /// A mount that was not declared by the administrator was requested
+    #[display(fmt = "unknown_mount")]
+    UnknownMount,
+    /// The mount is declared but not allowed by the client's policy
+    #[display(fmt = "mount_not_allowed")]
+    MountNotAllowed,
+    /// The requested priority is above the client policy's `max_priority`
+    #[display(fmt = "priority_not_allowed")]
+    PriorityNotAllowed,
+    /// The API key is missing or invalid
+    #[display(fmt = "unauthorized")]
+    Unauthorized,
+    /// The client already has `max_concurrent_runs` runs in progress
+    #[display(fmt = "concurrency_limit_exceeded")]
+    ConcurrencyLimitExceeded,
+    /// The client sent more requests than its rate limit allows, retry after `details.retry_after`
+    #[display(fmt = "rate_limit_exceeded")]
+    RateLimitExceeded,
+    /// The client used its runs or CPU time quota of the current window
+    #[display(fmt = "quota_exceeded")]
+    QuotaExceeded,
+    /// No job with this ID is in progress for the client
+    #[display(fmt = "job_not_found")]
+    JobNotFound,
+    /// A job with the same request ID is already in progress
+    #[display(fmt = "job_already_exists")]
+    JobAlreadyExists,
+    /// The run was cancelled before any phase ran
+    #[display(fmt = "cancelled")]
+    Cancelled,
+    #[display(fmt = "box_init_failed")]
+    BoxInitFailed,
+    #[display(fmt = "file_upload_failed")]
+    FileUploadFailed,
+    /// The sandbox failed to run a command, this is never caused by the submitted code
+    #[display(fmt = "sandbox_error")]
+    SandboxError,
+}
+
+#[derive(Debug, Serialize, Default, Clone)]
+pub struct ApiErrorDetails {
+    /// JSON path of the offending field, e.g. `phases[2].sandbox_settings.memory_limit`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+
+    /// Policy field of the exceeded limit, e.g. `max_runs_per_window`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<String>,
+
+    /// Maximum allowed value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<Value>,
+
+    /// Value given in the request, or current usage of a quota
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+
+    /// Seconds to wait before retrying, also sent in the `Retry-After` header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Violation {
+    pub code: ApiErrorCode,
+
+    /// JSON path of the offending field, e.g. `phases[2].sandbox_settings.memory_limit`
+    pub field: String,
+    pub reason: String,
+
+    /// Allowed range
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<Value>,
+
+    /// Value given in the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+}
+
+impl Violation {
+    pub fn new<S1, S2>(code: ApiErrorCode, field: S1, reason: S2) -> Violation
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        Violation {
+            code,
+            field: field.into(),
+            reason: reason.into(),
+            min: None,
+            max: None,
+            value: None,
+        }
+    }
+
+    pub fn with_max<T: Serialize>(mut self, max: T) -> Violation {
+        self.max = serde_json::to_value(max).ok();
+        self
+    }
+
+    pub fn with_value<T: Serialize>(mut self, value: T) -> Violation {
+        self.value = serde_json::to_value(value).ok();
+        self
+    }
+
+    /// Violation of a field reported by a `validator` error
+    pub fn from_validation_error<S: Into<String>>(field: S, error: &ValidationError) -> Violation {
+        // Custom validators can use an `ApiErrorCode` as their code
+        let code = serde_json::from_value(Value::String(error.code.to_string()))
+            .unwrap_or(ApiErrorCode::InvalidRequest);
+
+        Violation {
+            code,
+            field: field.into(),
+            reason: match &error.message {
+                Some(message) => message.to_string(),
+                None => format!("failed '{}' validation", error.code),
+            },
+            min: error.params.get("min").cloned(),
+            max: error.params.get("max").cloned(),
+            // Custom validators report the measured value in `actual`
+            value: error
+                .params
+                .get("actual")
+                .or_else(|| error.params.get("value"))
+                .cloned(),
+        }
+    }
+
+    /// Flattens errors reported by `validator`, nested structs and lists included.
+    pub fn from_validation_errors(errors: &ValidationErrors) -> Vec<Violation> {
+        fn collect(path: &str, errors: &ValidationErrors, violations: &mut Vec<Violation>) {
+            for (field, kind) in errors.errors() {
+                let field_path = match path {
+                    "" => field.to_string(),
+                    _ => format!("{}.{}", path, field),
+                };
+
+                match kind {
+                    ValidationErrorsKind::Field(errors) => {
+                        for error in errors {
+                            violations.push(Violation::from_validation_error(&field_path, error));
+                        }
+                    }
+                    ValidationErrorsKind::Struct(errors) => {
+                        collect(&field_path, errors, violations)
+                    }
+                    ValidationErrorsKind::List(errors) => {
+                        for (i, errors) in errors {
+                            collect(&format!("{}[{}]", field_path, i), errors, violations);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut violations = vec![];
+        collect("", errors, &mut violations);
+
+        violations
+    }
+
+    /// Sorts violations by field to keep responses stable, indexes are compared as numbers so
+    /// that `phases[2]` comes before `phases[10]`.
+    pub fn sort(violations: &mut [Violation]) {
+        #[derive(PartialEq, Eq, PartialOrd, Ord)]
+        enum Part<'a> {
+            Name(&'a str),
+            Index(usize),
+        }
+
+        fn parts(field: &str) -> Vec<Part<'_>> {
+            // Indexes are found between brackets, at odd positions
+            field
+                .split(['[', ']'])
+                .enumerate()
+                .map(|(i, part)| match (i % 2, part.parse()) {
+                    (1, Ok(index)) => Part::Index(index),
+                    _ => Part::Name(part),
+                })
+                .collect()
+        }
+
+        violations.sort_by(|a, b| parts(&a.field).cmp(&parts(&b.field)));
+    }
+}
+
 #[derive(Debug, Serialize, Display, Error)]
-#[display(fmt = "API Error {}: {}", status, message)]
+#[display(fmt = "API Error {} ({}): {}", status, code, message)]
 pub struct ApiError {
     pub status: u16,
+    pub code: ApiErrorCode,
     pub message: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Box<ApiErrorDetails>>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<Violation>,
 }
 
 impl ApiError {
-    pub fn new<S: Into<String>>(status: StatusCode, message: S) -> ApiError {
+    pub fn new<S: Into<String>>(status: StatusCode, code: ApiErrorCode, message: S) -> ApiError {
         ApiError {
             status: status.as_u16(),
+            code,
             message: message.into(),
+            details: None,
+            violations: vec![],
+        }
+    }
+
+    /// 422 error listing every violation of the request body
+    pub fn validation(violations: Vec<Violation>) -> ApiError {
+        ApiError {
+            violations,
+            ..ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ApiErrorCode::ValidationFailed,
+                "The request body is invalid",
+            )
         }
     }
 
-    pub fn not_found<S: Into<String>>(message: S) -> ApiError {
-        ApiError::new(StatusCode::NOT_FOUND, message)
+    pub fn not_found<S: Into<String>>(code: ApiErrorCode, message: S) -> ApiError {
+        ApiError::new(StatusCode::NOT_FOUND, code, message)
     }
 
-    pub fn bad_request<S: Into<String>>(message: S) -> ApiError {
-        ApiError::new(StatusCode::BAD_REQUEST, message)
+    pub fn bad_request<S: Into<String>>(code: ApiErrorCode, message: S) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, code, message)
     }
 
-    pub fn internal_server_error<S: Into<String>>(message: S) -> ApiError {
-        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    pub fn internal_server_error<S: Into<String>>(code: ApiErrorCode, message: S) -> ApiError {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, code, message)
+    }
+
+    pub fn too_many_requests<S: Into<String>>(code: ApiErrorCode, message: S) -> ApiError {
+        ApiError::new(StatusCode::TOO_MANY_REQUESTS, code, message)
+    }
+
+    pub fn with_details(mut self, details: ApiErrorDetails) -> ApiError {
+        self.details = Some(Box::new(details));
+        self
+    }
+
+    pub fn with_field<S: Into<String>>(self, field: S) -> ApiError {
+        self.with_details(ApiErrorDetails {
+            field: Some(field.into()),
+            ..Default::default()
+        })
     }
 }
 
@@ -51,6 +313,13 @@ impl ResponseError for ApiError {
             HeaderValue::from_static("application/json; charset=utf-8"),
         );
 
+        if let Some(retry_after) = self.details.as_ref().and_then(|d| d.retry_after) {
+            resp.headers_mut().insert(
+                RETRY_AFTER,
+                HeaderValue::from(retry_after.ceil().max(1.0) as u64),
+            );
+        }
+
         resp.set_body(Body::from(serde_json::to_string(&self).unwrap()))
     }
 }
@@ -66,3 +335,101 @@ impl<T> From<ApiError> for ActixResult<T> {
         Err(error.into())
     }
 }
+
+/// JSON request body, like `web::Json`, but deserialization errors give the path of the invalid
+/// value in `details.field`, e.g. `phases[2].sandbox_settings.memory_limit`. The size of the body
+/// is limited by the `PayloadConfig`.
+pub struct JsonBody<T>(pub T);
+
+impl<T> JsonBody<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for JsonBody<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for JsonBody<T> {
+    type Error = ApiError;
+    type Future = Pin<Box<dyn Future<Output = Result<JsonBody<T>, ApiError>>>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let is_json = matches!(
+            req.mime_type(),
+            Ok(Some(mime)) if mime.subtype() == "json" || mime.suffix().is_some_and(|suffix| suffix == "json")
+        );
+        let bytes = Bytes::from_request(req, payload);
+
+        Box::pin(async move {
+            if !is_json {
+                return ApiError::bad_request(
+                    ApiErrorCode::InvalidRequest,
+                    "Content type error, application/json is expected",
+                )
+                .into();
+            }
+
+            let body = bytes
+                .await
+                .map_err(|e| ApiError::bad_request(ApiErrorCode::InvalidRequest, e.to_string()))?;
+
+            serde_json::from_slice(&body).map(JsonBody).map_err(|e| {
+                let error = ApiError::bad_request(
+                    ApiErrorCode::InvalidRequest,
+                    format!("Json deserialize error: {}", e),
+                );
+
+                match json_path::path_at(&body, e.line(), e.column()) {
+                    path if path.is_empty() || !e.is_data() => error,
+                    path => error.with_field(path),
+                }
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn violations_are_sorted_by_index() {
+        let mut violations = [
+            "phases[10].name",
+            "phases[2].stdin",
+            "sandbox_settings",
+            "phases[2]",
+            "environment.B",
+            "phases[2].environment.A",
+            "environment",
+        ]
+        .iter()
+        .map(|field| Violation::new(ApiErrorCode::InvalidRequest, *field, ""))
+        .collect::<Vec<_>>();
+
+        Violation::sort(&mut violations);
+
+        assert_eq!(
+            violations
+                .iter()
+                .map(|v| v.field.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "environment",
+                "environment.B",
+                "phases[2]",
+                "phases[2].environment.A",
+                "phases[2].stdin",
+                "phases[10].name",
+                "sandbox_settings",
+            ]
+        );
+    }
+}
@@ -0,0 +1,496 @@
+use crate::api_helpers::{ApiError, ApiErrorCode};
+use crate::scheduler::Priority;
+use crate::usage;
+use crate::utils::{self, units};
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::http::StatusCode;
+use actix_web::{FromRequest, HttpRequest};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use std::{env, io};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// Maximum values of the sandbox settings. Unset values fall back to the `MAX_*` environment variables.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PolicyLimits {
+    #[serde(default, deserialize_with = "units::deserialize_seconds")]
+    pub run_time_limit: Option<f64>,
+    #[serde(default, deserialize_with = "units::deserialize_seconds")]
+    pub extra_time_limit: Option<f64>,
+    #[serde(default, deserialize_with = "units::deserialize_seconds")]
+    pub wall_time_limit: Option<f64>,
+    #[serde(default, deserialize_with = "units::deserialize_kilobytes")]
+    pub stack_size_limit: Option<u64>,
+    pub process_count_limit: Option<u64>,
+    #[serde(default, deserialize_with = "units::deserialize_kilobytes")]
+    pub memory_limit: Option<u64>,
+    #[serde(default, deserialize_with = "units::deserialize_kilobytes")]
+    pub storage_limit: Option<u64>,
+    pub open_files_limit: Option<u64>,
+    #[serde(default, deserialize_with = "units::deserialize_kilobytes")]
+    pub core_size_limit: Option<u64>,
+    #[serde(default, deserialize_with = "units::deserialize_kilobytes")]
+    pub address_space_limit: Option<u64>,
+    pub disk_quota_blocks: Option<u64>,
+    pub disk_quota_inodes: Option<u64>,
+}
+
+/// What a client is allowed to request. Unset values fall back to the environment variables.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Policy {
+    #[serde(default)]
+    pub limits: PolicyLimits,
+
+    pub allow_profiling: Option<bool>,
+    pub allow_network: Option<bool>,
+    pub allow_cg_timing_toggle: Option<bool>,
+
+    /// Names of the declared mounts the client can use, all of them when unset
+    pub mounts: Option<Vec<String>>,
+
+    /// Runs executed at the same time by the client
+    pub max_concurrent_runs: Option<usize>,
+
+    /// Token bucket refilled at `requests_per_second`, holding up to `burst` requests
+    pub requests_per_second: Option<f64>,
+    pub burst: Option<f64>,
+
+    /// Rolling window of the quotas
+    #[serde(default, deserialize_with = "units::deserialize_seconds")]
+    pub quota_window: Option<f64>,
+    pub max_runs_per_window: Option<u64>,
+    /// CPU time of the phases
+    #[serde(default, deserialize_with = "units::deserialize_seconds")]
+    pub max_cpu_time_per_window: Option<f64>,
+
+    /// Priority of the runs that do not ask for one
+    pub priority: Option<Priority>,
+    /// Highest priority the runs can ask for
+    pub max_priority: Option<Priority>,
+    /// Share of the queued runs started for the client, relative to the other clients
+    pub weight: Option<f64>,
+}
+
+impl Policy {
+    pub fn allow_profiling(&self) -> bool {
+        self.allow_profiling
+            .unwrap_or_else(|| utils::parsed_env::get_bool("ALLOW_PROFILING", true))
+    }
+
+    pub fn allow_network(&self) -> bool {
+        self.allow_network
+            .unwrap_or_else(|| utils::parsed_env::get_bool("ALLOW_NETWORK", false))
+    }
+
+    pub fn allow_cg_timing_toggle(&self) -> bool {
+        self.allow_cg_timing_toggle
+            .unwrap_or_else(|| utils::parsed_env::get_bool("ALLOW_CG_TIMING_TOGGLE", false))
+    }
+
+    pub fn allows_mount(&self, name: &str) -> bool {
+        match &self.mounts {
+            Some(mounts) => mounts.iter().any(|mount| mount == name),
+            None => true,
+        }
+    }
+
+    pub fn max_concurrent_runs(&self) -> Option<usize> {
+        self.max_concurrent_runs
+            .or_else(|| utils::parsed_env::get_optional("MAX_CONCURRENT_RUNS"))
+    }
+
+    /// Rate limiting is disabled unless positive
+    pub fn requests_per_second(&self) -> Option<f64> {
+        self.requests_per_second
+            .or_else(|| utils::parsed_env::get_optional("RATE_LIMIT_REQUESTS_PER_SECOND"))
+            .filter(|requests_per_second| *requests_per_second > 0.0)
+    }
+
+    /// Defaults to one second worth of requests
+    pub fn burst(&self) -> Option<f64> {
+        let requests_per_second = self.requests_per_second()?;
+
+        Some(
+            self.burst
+                .or_else(|| utils::parsed_env::get_optional("RATE_LIMIT_BURST"))
+                .unwrap_or_else(|| requests_per_second.max(1.0)),
+        )
+    }
+
+    /// Windows too large for a `Duration` are refused at startup, see `init`
+    pub fn quota_window(&self) -> Duration {
+        let seconds = self
+            .quota_window
+            .unwrap_or_else(|| utils::parsed_env::get("QUOTA_WINDOW", 3600.0));
+
+        Duration::try_from_secs_f64(seconds.max(0.0)).unwrap_or(Duration::MAX)
+    }
+
+    pub fn max_runs_per_window(&self) -> Option<u64> {
+        self.max_runs_per_window
+            .or_else(|| utils::parsed_env::get_optional("MAX_RUNS_PER_WINDOW"))
+    }
+
+    pub fn max_cpu_time_per_window(&self) -> Option<f64> {
+        self.max_cpu_time_per_window
+            .or_else(|| utils::parsed_env::get_optional("MAX_CPU_TIME_PER_WINDOW"))
+    }
+
+    /// Never above `max_priority`
+    pub fn priority(&self) -> Priority {
+        self.priority.unwrap_or_default().min(self.max_priority())
+    }
+
+    pub fn max_priority(&self) -> Priority {
+        self.max_priority
+            .unwrap_or_else(|| utils::parsed_env::get("MAX_PRIORITY", Priority::Interactive))
+    }
+
+    pub fn weight(&self) -> f64 {
+        self.weight.filter(|weight| *weight > 0.0).unwrap_or(1.0)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    /// Hex-encoded SHA-256 of the key, the key itself is never stored
+    pub sha256: String,
+
+    #[serde(default)]
+    pub policy: Policy,
+}
+
+/// API keys declared by the administrator in the `API_KEYS` environment variable, a JSON object
+/// mapping a client name to its key, e.g. `{"ci": {"sha256": "9f86d08...", "policy": {"allow_network": true}}}`.
+/// Authentication is disabled when no key is declared.
+fn declared_api_keys() -> Result<HashMap<String, ApiKey>, String> {
+    match env::var("API_KEYS") {
+        Ok(value) if !value.trim().is_empty() => parse_api_keys(&value)
+            .map_err(|e| format!("Failed to parse environment variable 'API_KEYS': {}", e)),
+        _ => Ok(HashMap::new()),
+    }
+}
+
+fn parse_api_keys(value: &str) -> Result<HashMap<String, ApiKey>, String> {
+    let api_keys: HashMap<String, ApiKey> =
+        serde_json::from_str(value).map_err(|e| e.to_string())?;
+
+    for (name, api_key) in &api_keys {
+        if let Some(quota_window) = api_key.policy.quota_window {
+            check_quota_window(quota_window)
+                .map_err(|e| format!("invalid policy of '{}': {}", name, e))?;
+        }
+    }
+
+    Ok(api_keys)
+}
+
+/// Quota windows are kept as a `Duration`, which can't be infinite or longer than `u64::MAX` seconds
+fn check_quota_window(seconds: f64) -> Result<(), String> {
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("invalid quota window '{}'", seconds)),
+    }
+}
+
+lazy_static! {
+    /// Parsed once, a malformed `API_KEYS` never disables authentication
+    static ref API_KEYS: Result<HashMap<String, ApiKey>, String> = declared_api_keys();
+}
+
+/// Checks `API_KEYS` and `QUOTA_WINDOW` at startup, godbox refuses to start when they are malformed.
+pub fn init() -> io::Result<()> {
+    API_KEYS
+        .as_ref()
+        .map_err(String::clone)
+        .and_then(|_| {
+            check_quota_window(utils::parsed_env::get("QUOTA_WINDOW", 3600.0))
+                .map_err(|e| format!("Invalid environment variable 'QUOTA_WINDOW': {}", e))
+        })
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Declared key matching `key`, along with its name. Keys are compared by their SHA-256.
+fn find_api_key<'a>(
+    api_keys: &'a HashMap<String, ApiKey>,
+    key: &str,
+) -> Option<(&'a String, &'a ApiKey)> {
+    let hash = format!("{:x}", Sha256::digest(key.trim().as_bytes()));
+
+    api_keys
+        .iter()
+        .find(|(_, api_key)| api_key.sha256.eq_ignore_ascii_case(&hash))
+}
+
+/// Caller of the API, along with its policy
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// Name of the API key, `None` when authentication is disabled
+    pub name: Option<String>,
+    /// IP address of the caller, see `TRUST_FORWARDED_FOR`
+    pub address: Option<String>,
+    pub policy: Policy,
+}
+
+fn request_api_key(req: &HttpRequest) -> Option<&str> {
+    let headers = req.headers();
+
+    match headers.get(API_KEY_HEADER) {
+        Some(value) => value.to_str().ok(),
+        None => headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer ")),
+    }
+}
+
+fn forwarded_address(value: &str) -> Option<IpAddr> {
+    value
+        .parse::<IpAddr>()
+        .or_else(|_| value.parse::<SocketAddr>().map(|address| address.ip()))
+        .ok()
+}
+
+/// IP address of the caller, without its port. Behind a trusted proxy, only the right-most
+/// `X-Forwarded-For` address was added by the proxy, the previous ones are given by the client.
+/// The header map does not keep the order of repeated headers, the proxy's address can't be told
+/// apart when the header is sent several times: the peer address is used instead.
+fn request_address(req: &HttpRequest, trust_forwarded_for: bool) -> Option<String> {
+    let mut forwarded_for = req.headers().get_all(FORWARDED_FOR_HEADER);
+
+    let forwarded = match (
+        trust_forwarded_for,
+        forwarded_for.next(),
+        forwarded_for.next(),
+    ) {
+        (true, Some(value), None) => value
+            .to_str()
+            .ok()
+            .and_then(|value| value.rsplit(',').map(str::trim).find(|v| !v.is_empty()))
+            .and_then(forwarded_address),
+        _ => None,
+    };
+
+    forwarded
+        .or_else(|| req.peer_addr().map(|address| address.ip()))
+        .map(|address| address.to_string())
+}
+
+impl Client {
+    pub fn authenticate(req: &HttpRequest) -> Result<Client, ApiError> {
+        let api_keys = match API_KEYS.as_ref() {
+            Ok(api_keys) => api_keys,
+            Err(_) => {
+                return ApiError::internal_server_error(
+                    ApiErrorCode::InternalError,
+                    "Authentication is misconfigured",
+                )
+                .into()
+            }
+        };
+        let address = request_address(
+            req,
+            utils::parsed_env::get_bool("TRUST_FORWARDED_FOR", false),
+        );
+
+        if api_keys.is_empty() {
+            return Ok(Client {
+                name: None,
+                address,
+                policy: Policy::default(),
+            });
+        }
+
+        let key = match request_api_key(req) {
+            Some(v) => v,
+            None => {
+                return ApiError::new(
+                    StatusCode::UNAUTHORIZED,
+                    ApiErrorCode::Unauthorized,
+                    "An API key is required",
+                )
+                .into()
+            }
+        };
+
+        match find_api_key(api_keys, key) {
+            Some((name, api_key)) => {
+                tracing::Span::current().record("client", name.as_str());
+
+                Ok(Client {
+                    name: Some(name.clone()),
+                    address,
+                    policy: api_key.policy.clone(),
+                })
+            }
+            None => ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                ApiErrorCode::Unauthorized,
+                "Invalid API key",
+            )
+            .into(),
+        }
+    }
+
+    /// Key of the client's usage: the API key name, or the IP address when authentication is disabled
+    pub fn id(&self) -> String {
+        match (&self.name, &self.address) {
+            (Some(name), _) => format!("key:{}", name),
+            (None, Some(address)) => format!("ip:{}", address),
+            (None, None) => "anonymous".into(),
+        }
+    }
+}
+
+/// Authenticates the request. Cancellations and usage requests are not rate limited, so that a
+/// client over its limit can still stop its runs and see when to retry.
+impl FromRequest for Client {
+    type Error = ApiError;
+    type Future = Ready<Result<Client, ApiError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Client::authenticate(req))
+    }
+}
+
+/// Authenticated client whose request took a token from its rate limit bucket
+pub struct RateLimitedClient(pub Client);
+
+impl FromRequest for RateLimitedClient {
+    type Error = ApiError;
+    type Future = Ready<Result<RateLimitedClient, ApiError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Client::authenticate(req).and_then(|client| {
+            usage::check_rate_limit(&client)?;
+
+            Ok(RateLimitedClient(client))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    // SHA-256 of "secret"
+    const SECRET_SHA256: &str = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+    fn address(request: TestRequest, trust_forwarded_for: bool) -> Option<String> {
+        request_address(&request.to_http_request(), trust_forwarded_for)
+    }
+
+    fn peer() -> TestRequest {
+        TestRequest::default().peer_addr("10.0.0.1:4321".parse().unwrap())
+    }
+
+    #[test]
+    fn keys_are_found_by_their_hash() {
+        let api_keys = parse_api_keys(&format!(
+            r#"{{"ci": {{"sha256": "{}"}}, "upper": {{"sha256": "{}"}}}}"#,
+            "0".repeat(64),
+            SECRET_SHA256.to_uppercase()
+        ))
+        .unwrap();
+
+        let (name, _) = find_api_key(&api_keys, "secret").unwrap();
+        assert_eq!(name, "upper");
+        // Surrounding whitespace is ignored
+        assert_eq!(find_api_key(&api_keys, " secret\n").unwrap().0, "upper");
+
+        assert!(find_api_key(&api_keys, "Secret").is_none());
+        assert!(find_api_key(&api_keys, SECRET_SHA256).is_none());
+        assert!(find_api_key(&api_keys, "").is_none());
+    }
+
+    #[test]
+    fn malformed_api_keys_are_refused() {
+        assert!(parse_api_keys("{}").unwrap().is_empty());
+        assert!(parse_api_keys("[]").is_err());
+        assert!(parse_api_keys(r#"{"ci": {}}"#).is_err());
+        assert!(parse_api_keys(
+            r#"{"ci": {"sha256": "00", "policy": {"limits": {"memory_limit": "1XB"}}}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn peer_address_without_a_trusted_proxy() {
+        let request = peer().header(FORWARDED_FOR_HEADER, "1.2.3.4");
+
+        assert_eq!(address(request, false).as_deref(), Some("10.0.0.1"));
+        assert_eq!(address(TestRequest::default(), false), None);
+    }
+
+    #[test]
+    fn right_most_forwarded_address_behind_a_trusted_proxy() {
+        let request = peer().header(FORWARDED_FOR_HEADER, "6.6.6.6, 7.7.7.7, 5.6.7.8");
+        assert_eq!(address(request, true).as_deref(), Some("5.6.7.8"));
+
+        // Ports are dropped, IPv6 addresses included
+        let request = peer().header(FORWARDED_FOR_HEADER, "6.6.6.6, 1.2.3.4:5678");
+        assert_eq!(address(request, true).as_deref(), Some("1.2.3.4"));
+
+        let request = peer().header(FORWARDED_FOR_HEADER, "[2001:db8::1]:443");
+        assert_eq!(address(request, true).as_deref(), Some("2001:db8::1"));
+
+        let request = peer().header(FORWARDED_FOR_HEADER, "2001:db8::2");
+        assert_eq!(address(request, true).as_deref(), Some("2001:db8::2"));
+
+        // Empty entries are skipped
+        let request = peer().header(FORWARDED_FOR_HEADER, "1.2.3.4, ");
+        assert_eq!(address(request, true).as_deref(), Some("1.2.3.4"));
+    }
+
+    #[test]
+    fn peer_address_when_the_forwarded_header_is_repeated() {
+        let request = peer()
+            .header(FORWARDED_FOR_HEADER, "6.6.6.6")
+            .header(FORWARDED_FOR_HEADER, "1.2.3.4");
+        assert_eq!(address(request, true).as_deref(), Some("10.0.0.1"));
+
+        let request = peer()
+            .header(FORWARDED_FOR_HEADER, "6.6.6.6")
+            .header(FORWARDED_FOR_HEADER, "7.7.7.7")
+            .header(FORWARDED_FOR_HEADER, "1.2.3.4");
+        assert_eq!(address(request, true).as_deref(), Some("10.0.0.1"));
+    }
+
+    #[test]
+    fn peer_address_when_the_forwarded_address_is_invalid() {
+        let request = peer().header(FORWARDED_FOR_HEADER, "1.2.3.4, unknown");
+        assert_eq!(address(request, true).as_deref(), Some("10.0.0.1"));
+
+        assert_eq!(address(peer(), true).as_deref(), Some("10.0.0.1"));
+    }
+
+    #[test]
+    fn out_of_range_quota_windows_are_refused() {
+        let api_keys = |quota_window: &str| {
+            parse_api_keys(&format!(
+                r#"{{"ci": {{"sha256": "00", "policy": {{"quota_window": {}}}}}}}"#,
+                quota_window
+            ))
+        };
+
+        assert!(api_keys("3600").is_ok());
+        assert!(api_keys(r#""1e5h""#).is_ok());
+        assert!(api_keys("1e20")
+            .unwrap_err()
+            .contains("invalid quota window"));
+        assert!(api_keys(r#""1e17h""#).is_err());
+        assert!(check_quota_window(f64::INFINITY).is_err());
+        assert!(check_quota_window(-1.0).is_err());
+    }
+}
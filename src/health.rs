@@ -0,0 +1,145 @@
+use crate::isolate::{self, Isolate, IsolatedBoxInitOptionsBuilder};
+use crate::utils;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ReadinessCheck {
+    pub name: &'static str,
+    pub ok: bool,
+    pub message: String,
+    /// Seconds
+    pub duration: f64,
+}
+
+impl ReadinessCheck {
+    fn run<F>(name: &'static str, check: F) -> ReadinessCheck
+    where
+        F: FnOnce() -> Result<String, String>,
+    {
+        let start = Instant::now();
+        let result = check();
+        let duration = start.elapsed().as_secs_f64();
+
+        if let Err(e) = &result {
+            tracing::warn!(check = name, "Readiness check failed: {}", e);
+        }
+
+        ReadinessCheck {
+            name,
+            ok: result.is_ok(),
+            message: result.unwrap_or_else(|e| e),
+            duration,
+        }
+    }
+}
+
+fn check_isolate_binary() -> Result<String, String> {
+    match isolate::exec_command(vec!["isolate", "--version"], None, None, None) {
+        Ok(result) if result.status.success() => {
+            Ok(result.stdout.lines().next().unwrap_or_default().into())
+        }
+        Ok(result) => Err(format!(
+            "isolate --version failed: {}",
+            result.stderr.trim()
+        )),
+        Err(e) => Err(format!("isolate is not executable: {}", e)),
+    }
+}
+
+fn check_cgroups() -> Result<String, String> {
+    // cgroup v2 lists its controllers in a single file, v1 has a hierarchy per controller
+    match fs::read_to_string("/sys/fs/cgroup/cgroup.controllers") {
+        Ok(controllers) if controllers.split_whitespace().any(|c| c == "memory") => {
+            Ok("cgroup v2 memory controller available".into())
+        }
+        Ok(_) => Err("cgroup v2 memory controller is not enabled".into()),
+        Err(_) if Path::new("/sys/fs/cgroup/memory").is_dir() => {
+            Ok("cgroup v1 memory controller available".into())
+        }
+        Err(_) => Err("no memory cgroup controller found in /sys/fs/cgroup".into()),
+    }
+}
+
+fn check_box_lifecycle() -> Result<String, String> {
+    let mut isolate = Isolate::new();
+
+    let isolated_box = isolate
+        .init_box(IsolatedBoxInitOptionsBuilder::default().build().unwrap())
+        .map_err(|e| format!("failed to initialize a box: {}", e))?;
+
+    isolate
+        .destroy_box(isolated_box.box_id)
+        .map_err(|e| format!("failed to cleanup the box {}: {}", isolated_box.box_id, e))?;
+
+    Ok(format!(
+        "box {} initialized and cleaned",
+        isolated_box.box_id
+    ))
+}
+
+fn check_disk_space() -> Result<String, String> {
+    let path = utils::parsed_env::get("ISOLATE_BOX_ROOT", String::from("/var/local/lib/isolate"));
+    let min_free = utils::parsed_env::get("MIN_FREE_DISK_SPACE", 1048576u64);
+
+    let result = isolate::exec_command(vec!["df", "-Pk", &path], None, None, None)
+        .map_err(|e| format!("failed to run df: {}", e))?;
+
+    if !result.status.success() {
+        return Err(format!("df failed on {}: {}", path, result.stderr.trim()));
+    }
+
+    // POSIX output: header, then "filesystem blocks used available capacity mountpoint"
+    let free = result
+        .stdout
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|available| available.parse::<u64>().ok())
+        .ok_or_else(|| format!("unexpected df output: {}", result.stdout.trim()))?;
+
+    match free >= min_free {
+        true => Ok(format!("{} KB free on {}", free, path)),
+        false => Err(format!(
+            "{} KB free on {}, at least {} KB required",
+            free, path, min_free
+        )),
+    }
+}
+
+/// Runs every readiness check, in order, even if one fails.
+fn readiness_checks() -> Vec<ReadinessCheck> {
+    vec![
+        ReadinessCheck::run("isolate_binary", check_isolate_binary),
+        ReadinessCheck::run("cgroups", check_cgroups),
+        ReadinessCheck::run("box_lifecycle", check_box_lifecycle),
+        ReadinessCheck::run("disk_space", check_disk_space),
+    ]
+}
+
+lazy_static! {
+    /// Last checks and when they ran
+    static ref LAST_READINESS_CHECKS: Mutex<Option<(Instant, Vec<ReadinessCheck>)>> = Mutex::new(None);
+}
+
+/// Readiness checks, reused for `READINESS_CACHE_DURATION` seconds so probes can't create boxes
+/// on every request. Concurrent callers wait for the checks in progress instead of running their own.
+pub fn cached_readiness_checks() -> Vec<ReadinessCheck> {
+    let cache_duration = utils::parsed_env::get("READINESS_CACHE_DURATION", 5.0);
+    let mut last_checks = LAST_READINESS_CHECKS.lock().unwrap();
+
+    if let Some((checked_at, checks)) = &*last_checks {
+        if checked_at.elapsed().as_secs_f64() < cache_duration {
+            return checks.clone();
+        }
+    }
+
+    let checks = readiness_checks();
+
+    *last_checks = Some((Instant::now(), checks.clone()));
+
+    checks
+}
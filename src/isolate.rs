@@ -1,15 +1,19 @@
+use derive_more::{Display, Error, From};
 use rand::{thread_rng, Rng};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::fs::{self, File};
 use std::io;
 use std::io::prelude::*;
 use std::os::unix::prelude::ExitStatusExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::process::ExitStatus;
+use std::process::{Child, Command, Output};
+use std::str::FromStr;
+use std::thread;
 use std::{collections::HashMap, process::Stdio};
 
-use crate::utils;
+use crate::jobs::Job;
+use crate::{logging, utils};
 
 #[derive(Debug)]
 pub struct ExecutedCommandResult {
@@ -18,7 +22,7 @@ pub struct ExecutedCommandResult {
     pub stderr: String,
 }
 
-fn exec_command<I, S>(
+pub fn exec_command<I, S>(
     args: I,
     stdout: Option<Stdio>,
     stderr: Option<Stdio>,
@@ -28,22 +32,52 @@ where
     I: IntoIterator<Item = S>,
     S: Into<String>,
 {
-    let mut args_string: Vec<String> = args.into_iter().map(Into::into).collect();
+    exec_job_command(args, stdout, stderr, stdin, None)
+}
 
-    let program = args_string.remove(0);
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
 
-    println!(
-        "Executing command: {} {}",
-        program,
-        args_string.join(" ").to_string()
-    );
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buf)?;
+        }
 
-    let mut child = Command::new(program)
-        .args(args_string)
-        .stdout(stdout.unwrap_or(Stdio::piped()))
-        .stderr(stderr.unwrap_or(Stdio::piped()))
-        .stdin(Stdio::piped())
-        .spawn()?;
+        Ok(buf)
+    })
+}
+
+/// Waits for the process to exit without reaping it, its PID can't be reused in the meantime.
+fn wait_exited(pid: u32) -> io::Result<()> {
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+
+        let result = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+
+        if result == 0 {
+            return Ok(());
+        }
+
+        let error = io::Error::last_os_error();
+
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// Waits for the process and collects its output. The process stays tracked by `job` until it
+/// exited, and is only reaped once untracked so it is never interrupted after its PID is released.
+fn wait_command(mut child: Child, stdin: Option<String>, job: Option<&Job>) -> io::Result<Output> {
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
 
     if let Some(stdin_string) = stdin {
         child
@@ -53,7 +87,67 @@ where
             .write_all(stdin_string.as_bytes())?;
     }
 
-    let output = child.wait_with_output()?;
+    drop(child.stdin.take());
+
+    if let Some(job) = job {
+        let exited = wait_exited(child.id());
+
+        job.set_process(None);
+        exited?;
+    }
+
+    let status = child.wait()?;
+
+    let join = |reader: thread::JoinHandle<io::Result<Vec<u8>>>| {
+        reader
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("Failed to read the output")))
+    };
+
+    Ok(Output {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
+
+/// Same as [`exec_command`], the process is tracked by `job` while it runs so it can be interrupted.
+fn exec_job_command<I, S>(
+    args: I,
+    stdout: Option<Stdio>,
+    stderr: Option<Stdio>,
+    stdin: Option<String>,
+    job: Option<&Job>,
+) -> io::Result<ExecutedCommandResult>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let mut args_string: Vec<String> = args.into_iter().map(Into::into).collect();
+
+    tracing::debug!(command = %logging::command_line(&args_string), "Executing command");
+
+    let program = args_string.remove(0);
+
+    let child = Command::new(program)
+        .args(args_string)
+        .stdout(stdout.unwrap_or(Stdio::piped()))
+        .stderr(stderr.unwrap_or(Stdio::piped()))
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    if let Some(job) = job {
+        job.set_process(Some(child.id()));
+    }
+
+    let output = wait_command(child, stdin, job);
+
+    // Already untracked once the process exited, unless waiting for it failed
+    if let Some(job) = job {
+        job.set_process(None);
+    }
+
+    let output = output?;
 
     let stdout = String::from_utf8_lossy(&output.stdout).to_string();
     let stderr = String::from_utf8_lossy(&output.stderr).to_string();
@@ -65,6 +159,22 @@ where
     })
 }
 
+#[derive(Debug, Display, Error)]
+#[display(fmt = "Malformed isolate metadata at line {}: {}", line, reason)]
+pub struct IsolateMetadataError {
+    pub line: usize,
+    pub reason: String,
+}
+
+#[derive(Debug, Display, Error, From)]
+pub enum IsolateError {
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+
+    #[display(fmt = "{}", _0)]
+    Metadata(IsolateMetadataError),
+}
+
 #[derive(Default, Debug, Builder, Clone, Serialize)]
 #[builder(default)]
 pub struct IsolateMetadata {
@@ -74,38 +184,82 @@ pub struct IsolateMetadata {
     pub csw_voluntary: Option<u64>,
     pub csw_forced: Option<u64>,
     pub cg_mem: Option<u64>,
+    pub cg_enabled: bool,
+    pub cg_oom_killed: bool,
     pub exit_code: Option<i32>,
+    pub exit_signal: Option<i32>,
+    pub killed: bool,
+    pub message: Option<String>,
     pub status: Option<String>,
+
+    /// Keys this version of godbox does not know about
+    pub extra: HashMap<String, String>,
 }
 
-impl From<String> for IsolateMetadata {
-    fn from(string: String) -> Self {
+impl FromStr for IsolateMetadata {
+    type Err = IsolateMetadataError;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        fn parse<T: FromStr>(
+            line: usize,
+            key: &str,
+            value: &str,
+        ) -> Result<T, IsolateMetadataError> {
+            value.trim().parse().map_err(|_| IsolateMetadataError {
+                line,
+                reason: format!("invalid value '{}' for key '{}'", value, key),
+            })
+        }
+
+        fn parse_flag(line: usize, key: &str, value: &str) -> Result<bool, IsolateMetadataError> {
+            Ok(parse::<u8>(line, key, value)? != 0)
+        }
+
         let mut builder = IsolateMetadataBuilder::default();
+        let mut extra = HashMap::new();
 
-        for metadata in string.lines() {
-            let values = metadata.split(':').collect::<Vec<_>>();
+        for (index, metadata) in string.lines().enumerate() {
+            let line = index + 1;
 
-            if values.len() < 2 {
+            if metadata.trim().is_empty() {
                 continue;
             }
 
-            let key = values[0];
-            let value = values[1];
+            // Only split on the first colon, values such as `message` may contain some
+            let (key, value) = match metadata.split_once(':') {
+                Some(v) => v,
+                None => {
+                    return Err(IsolateMetadataError {
+                        line,
+                        reason: format!("missing ':' in '{}'", metadata),
+                    })
+                }
+            };
 
             match key {
-                "time" => builder.time(Some(value.parse().unwrap())),
-                "time-wall" => builder.time_wall(Some(value.parse().unwrap())),
-                "max-rss" => builder.max_rss(Some(value.parse().unwrap())),
-                "csw-voluntary" => builder.csw_voluntary(Some(value.parse().unwrap())),
-                "csw-forced" => builder.csw_forced(Some(value.parse().unwrap())),
-                "cg-mem" => builder.cg_mem(Some(value.parse().unwrap())),
-                "exitcode" => builder.exit_code(Some(value.parse().unwrap())),
+                "time" => builder.time(Some(parse(line, key, value)?)),
+                "time-wall" => builder.time_wall(Some(parse(line, key, value)?)),
+                "max-rss" => builder.max_rss(Some(parse(line, key, value)?)),
+                "csw-voluntary" => builder.csw_voluntary(Some(parse(line, key, value)?)),
+                "csw-forced" => builder.csw_forced(Some(parse(line, key, value)?)),
+                "cg-mem" => builder.cg_mem(Some(parse(line, key, value)?)),
+                "cg-enabled" => builder.cg_enabled(parse_flag(line, key, value)?),
+                "cg-oom-killed" => builder.cg_oom_killed(parse_flag(line, key, value)?),
+                "exitcode" => builder.exit_code(Some(parse(line, key, value)?)),
+                "exitsig" => builder.exit_signal(Some(parse(line, key, value)?)),
+                "killed" => builder.killed(parse_flag(line, key, value)?),
+                "message" => builder.message(Some(value.to_string())),
                 "status" => builder.status(Some(value.to_string())),
-                _ => &mut builder,
+                _ => {
+                    extra.insert(key.to_string(), value.to_string());
+                    &mut builder
+                }
             };
         }
 
-        builder.build().unwrap()
+        builder.extra(extra);
+
+        Ok(builder.build().unwrap())
     }
 }
 
@@ -127,11 +281,116 @@ pub struct IsolatedBox {
     metadata_file: String,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct IsolatedBoxMount {
+    /// Path inside the box
+    pub box_path: String,
+
+    /// Path on the host
+    pub host_path: String,
+
+    #[serde(default)]
+    pub read_write: bool,
+
+    /// Silently skip the mount when `host_path` does not exist
+    #[serde(default)]
+    pub optional: bool,
+}
+
+impl IsolatedBoxMount {
+    fn to_arg(&self) -> String {
+        let mut arg = format!("--dir={}={}", self.box_path, self.host_path);
+
+        if self.read_write {
+            arg.push_str(":rw");
+        }
+
+        if self.optional {
+            arg.push_str(":maybe");
+        }
+
+        arg
+    }
+}
+
+/// Variables available in every box, phases can override them. `HOME` and `PATH` can be
+/// overridden or completed by the administrator in the `BASE_ENVIRONMENT` environment variable,
+/// a JSON object mapping names to values.
+pub fn base_environment() -> HashMap<String, String> {
+    base_environment_with(std::env::var("BASE_ENVIRONMENT").ok().as_deref())
+}
+
+fn base_environment_with(declared: Option<&str>) -> HashMap<String, String> {
+    let mut environment = HashMap::new();
+
+    environment.insert("HOME".into(), "/tmp".into());
+    environment.insert(
+        "PATH".into(),
+        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".into(),
+    );
+
+    if let Some(value) = declared {
+        match serde_json::from_str::<HashMap<String, String>>(value) {
+            Ok(variables) => {
+                for (key, value) in variables {
+                    if utils::validators::is_valid_environment_key(&key) && !value.contains('\0') {
+                        environment.insert(key, value);
+                    } else {
+                        tracing::warn!(
+                            "Ignoring invalid variable '{}' of environment variable 'BASE_ENVIRONMENT'",
+                            key.escape_debug()
+                        );
+                    }
+                }
+            }
+            Err(e) => tracing::warn!(
+                "Failed to parse environment variable 'BASE_ENVIRONMENT': {}",
+                e
+            ),
+        }
+    }
+
+    environment
+}
+
+/// `-E` arguments of the variables. Arguments are passed as is to isolate (no shell involved),
+/// values must not be escaped. Sorted to keep the command line deterministic.
+fn environment_args(environment: &HashMap<String, String>) -> io::Result<Vec<String>> {
+    let mut variables = environment.iter().collect::<Vec<_>>();
+    variables.sort();
+
+    variables
+        .into_iter()
+        .map(|(key, value)| {
+            if !utils::validators::is_valid_environment_key(key) || value.contains('\0') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid environment variable '{}'", key.escape_debug()),
+                ));
+            }
+
+            Ok(format!("-E{}={}", key, value))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub enum IsolatedCommand {
+    /// Script uploaded into the box and run by the given interpreter
+    Script {
+        interpreter: String,
+        content: String,
+    },
+
+    /// Program and its arguments, executed without any wrapper
+    Argv(Vec<String>),
+}
+
 #[derive(Default, Debug, Builder, Clone)]
 #[builder(setter(into))]
 pub struct IsolatedBoxOptions {
-    #[builder(default)]
-    pub environment: Option<HashMap<String, String>>,
+    #[builder(default = "base_environment()")]
+    pub environment: HashMap<String, String>,
 
     #[builder(default)]
     pub stdin: Option<String>,
@@ -139,14 +398,14 @@ pub struct IsolatedBoxOptions {
     #[builder(default = "false")]
     pub profiling: bool,
 
-    #[builder(default = "utils::parsed_env::get(\"MAX_RUN_TIME_LIMIT\", 5)")]
-    pub run_time_limit: u64,
+    #[builder(default = "utils::parsed_env::get(\"MAX_RUN_TIME_LIMIT\", 5.0)")]
+    pub run_time_limit: f64,
 
-    #[builder(default = "utils::parsed_env::get(\"MAX_EXTRA_TIME_LIMIT\", 0)")]
-    pub extra_time_limit: u64,
+    #[builder(default = "utils::parsed_env::get(\"MAX_EXTRA_TIME_LIMIT\", 0.0)")]
+    pub extra_time_limit: f64,
 
-    #[builder(default = "utils::parsed_env::get(\"MAX_WALL_TIME_LIMIT\", 10)")]
-    pub wall_time_limit: u64,
+    #[builder(default = "utils::parsed_env::get(\"MAX_WALL_TIME_LIMIT\", 10.0)")]
+    pub wall_time_limit: f64,
 
     #[builder(default = "utils::parsed_env::get(\"MAX_STACK_SIZE_LIMIT\", 128000)")]
     pub stack_size_limit: u64,
@@ -159,16 +418,58 @@ pub struct IsolatedBoxOptions {
 
     #[builder(default = "utils::parsed_env::get(\"MAX_STORAGE_LIMIT\", 10240)")]
     pub storage_limit: u64,
+
+    #[builder(default = "utils::parsed_env::get(\"MAX_OPEN_FILES_LIMIT\", 64)")]
+    pub open_files_limit: u64,
+
+    #[builder(default = "utils::parsed_env::get(\"MAX_CORE_SIZE_LIMIT\", 0)")]
+    pub core_size_limit: u64,
+
+    /// Not passed to isolate when `None`, the address space is then unlimited
+    #[builder(default = "utils::parsed_env::get_optional(\"MAX_ADDRESS_SPACE_LIMIT\")")]
+    pub address_space_limit: Option<u64>,
+
+    #[builder(default = "true")]
+    pub cg_timing: bool,
+
+    #[builder(default)]
+    pub mounts: Vec<IsolatedBoxMount>,
+
+    #[builder(default = "false")]
+    pub network: bool,
+
+    /// Working directory inside the box, defaults to `/box`
+    #[builder(default)]
+    pub cwd: Option<String>,
+}
+
+#[derive(Default, Debug, Builder, Clone)]
+#[builder(setter(into))]
+pub struct IsolatedBoxInitOptions {
+    /// Disk quota as a number of blocks and inodes, isolate only accepts it when initializing a box
+    #[builder(default)]
+    pub disk_quota: Option<(u64, u64)>,
 }
 
 impl IsolatedBox {
-    pub fn new(box_id: u32) -> io::Result<IsolatedBox> {
-        let output = exec_command(
-            vec!["isolate", "--cg", &format!("-b {}", box_id), "--init"],
-            None,
-            None,
-            None,
-        )?;
+    pub fn new(box_id: u32, options: IsolatedBoxInitOptions) -> io::Result<IsolatedBox> {
+        let mut args = vec![
+            "isolate".to_string(),
+            "--cg".into(),
+            format!("-b {}", box_id),
+        ];
+
+        if let Some((blocks, inodes)) = options.disk_quota {
+            args.push(format!("--quota={},{}", blocks, inodes));
+        }
+
+        args.push("--init".into());
+
+        let output = exec_command(args, None, None, None)?;
+
+        if !output.status.success() {
+            return Err(io::Error::other(output.stderr));
+        }
 
         let workdir = output.stdout.trim().to_string();
 
@@ -219,14 +520,13 @@ impl IsolatedBox {
         Ok(Path::new(&file_absolute_path).to_owned())
     }
 
-    pub fn exec<S>(
+    /// Runs a command in the box, `job` can interrupt it.
+    pub fn exec(
         &self,
-        script: S,
+        command: IsolatedCommand,
         options: IsolatedBoxOptions,
-    ) -> io::Result<IsolatedExecutedCommandResult>
-    where
-        S: Into<String>,
-    {
+        job: Option<&Job>,
+    ) -> Result<IsolatedExecutedCommandResult, IsolateError> {
         let box_id_arg = format!("-b {}", self.box_id);
         let metadata_arg = format!("-M{}", self.metadata_file);
         let run_time_limit_arg = format!("-t {}", options.run_time_limit);
@@ -236,6 +536,8 @@ impl IsolatedBox {
         let process_count_limit_arg = format!("-p{}", options.process_count_limit);
         let memory_limit_arg = format!("--cg-mem={}", options.memory_limit);
         let storage_limit_arg = format!("-f {}", options.storage_limit);
+        let open_files_limit_arg = format!("--open-files={}", options.open_files_limit);
+        let core_size_limit_arg = format!("--core={}", options.core_size_limit);
 
         let isolate_args = vec![
             "isolate",
@@ -256,32 +558,45 @@ impl IsolatedBox {
             &stack_size_limit_arg,
             // Process count limit
             &process_count_limit_arg,
-            // Enable per process/thread time limit
-            "--cg-timing",
             // Memory limit in KB
             &memory_limit_arg,
             // Storage size limit in KB
             &storage_limit_arg,
+            // Open files limit
+            &open_files_limit_arg,
+            // Core file size limit in KB
+            &core_size_limit_arg,
         ];
 
-        let mut environment_variables = vec![
-            "-EHOME=/tmp".into(),
-            "-EPATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".into(),
-        ];
-
-        if let Some(environment) = options.environment.clone() {
-            for (key, value) in environment.iter() {
-                environment_variables.push(format!(
-                    "-E{}={}",
-                    key.replace("\\", "\\\\").replace("\"", "\\\""),
-                    value.replace("\\", "\\\\").replace("\"", "\\\"")
-                ));
-            }
-        }
+        let mut environment_variables = environment_args(&options.environment)?;
 
         let mut args: Vec<String> = vec![];
         args.append(&mut isolate_args.iter().map(|&v| v.into()).collect());
 
+        if options.cg_timing {
+            // Enable per process/thread time limit
+            args.push("--cg-timing".into());
+        }
+
+        if let Some(address_space_limit) = options.address_space_limit {
+            // Address space limit in KB
+            args.push(format!("--mem={}", address_space_limit));
+        }
+
+        if options.network {
+            // Share the host network namespace
+            args.push("--share-net".into());
+        }
+
+        for mount in options.mounts.iter() {
+            args.push(mount.to_arg());
+        }
+
+        if let Some(cwd) = &options.cwd {
+            // Working directory
+            args.push(format!("--chdir={}", cwd));
+        }
+
         args.append(&mut environment_variables);
 
         args.append(&mut vec![
@@ -290,13 +605,6 @@ impl IsolatedBox {
             "--".into(),
         ]);
 
-        let script_name = format!("/box/.script-{}.sh", thread_rng().gen::<u64>());
-
-        self.upload_file(
-            script_name.clone(),
-            format!("{}\n", script.into()).as_bytes(),
-        )?;
-
         if options.profiling {
             args.append(&mut vec![
                 "/usr/bin/perf_5.10".into(),
@@ -305,27 +613,47 @@ impl IsolatedBox {
             ]);
         }
 
-        args.append(&mut vec!["/bin/bash".into(), script_name.clone()]);
+        match command {
+            IsolatedCommand::Script {
+                interpreter,
+                content,
+            } => {
+                let script_name = format!("/box/.script-{}.sh", thread_rng().gen::<u64>());
+
+                tracing::debug!(
+                    script = logging::script(&content),
+                    "Uploading script to {}",
+                    script_name
+                );
+
+                self.upload_file(script_name.clone(), format!("{}\n", content).as_bytes())?;
+
+                args.append(&mut vec![interpreter, script_name]);
+            }
+            IsolatedCommand::Argv(mut argv) => args.append(&mut argv),
+        }
 
         let stdout_stream = File::create(self.stdout_file.clone())?;
         let stderr_stream = File::create(self.stderr_file.clone())?;
 
-        let result = exec_command(
+        let result = exec_job_command(
             args,
             Some(Stdio::from(stdout_stream)),
             Some(Stdio::from(stderr_stream)),
             options.stdin,
+            job,
         )?;
 
         let stdout = fs::read_to_string(self.stdout_file.clone())?;
         let stderr = fs::read_to_string(self.stderr_file.clone())?;
         let metadata_string = fs::read_to_string(self.metadata_file.clone())?;
 
-        let metadata = IsolateMetadata::from(metadata_string);
+        let metadata = metadata_string.parse::<IsolateMetadata>()?;
 
         Ok(IsolatedExecutedCommandResult {
             status: match metadata.exit_code {
-                Some(exit_code) => ExitStatus::from_raw(exit_code),
+                // `from_raw` expects a wait status, the exit code lives in its second byte
+                Some(exit_code) => ExitStatus::from_raw(exit_code << 8),
                 None => result.status,
             },
             stdout,
@@ -347,9 +675,9 @@ impl Isolate {
         }
     }
 
-    pub fn init_box(&mut self) -> io::Result<IsolatedBox> {
+    pub fn init_box(&mut self, options: IsolatedBoxInitOptions) -> io::Result<IsolatedBox> {
         let box_id = thread_rng().gen_range(0..=(i32::MAX as u32));
-        let isolated_box = IsolatedBox::new(box_id)?;
+        let isolated_box = IsolatedBox::new(box_id, options)?;
 
         self.boxes.insert(box_id, isolated_box.clone());
 
@@ -364,11 +692,160 @@ impl Isolate {
         exec_command(isolate_args, None, None, None)
     }
 
+    /// Cleans up a box. It is forgotten even if the cleanup fails, isolate cleans it up again
+    /// before reusing its ID.
     pub fn destroy_box(&mut self, isolated_box_id: u32) -> io::Result<()> {
-        self.cleanup(isolated_box_id)?;
-
         self.boxes.remove(&isolated_box_id);
 
-        Ok(())
+        let result = self.cleanup(isolated_box_id)?;
+
+        match result.status.success() {
+            true => Ok(()),
+            false => Err(io::Error::other(result.stderr.trim().to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_of_a_successful_run() {
+        let metadata: IsolateMetadata = "time:0.012\ntime-wall:0.043\nmax-rss:3456\ncsw-voluntary:4\ncsw-forced:1\ncg-mem:2048\ncg-enabled:1\nexitcode:0\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(metadata.time, Some(0.012));
+        assert_eq!(metadata.time_wall, Some(0.043));
+        assert_eq!(metadata.max_rss, Some(3456));
+        assert_eq!(metadata.csw_voluntary, Some(4));
+        assert_eq!(metadata.csw_forced, Some(1));
+        assert_eq!(metadata.cg_mem, Some(2048));
+        assert!(metadata.cg_enabled);
+        assert!(!metadata.cg_oom_killed);
+        assert_eq!(metadata.exit_code, Some(0));
+        assert_eq!(metadata.status, None);
+        assert!(metadata.extra.is_empty());
+    }
+
+    #[test]
+    fn message_containing_colons() {
+        let metadata: IsolateMetadata =
+            "status:XX\nmessage:execve(\"/box/a.out\"): No such file: or directory\n"
+                .parse()
+                .unwrap();
+
+        assert_eq!(metadata.status.as_deref(), Some("XX"));
+        assert_eq!(
+            metadata.message.as_deref(),
+            Some("execve(\"/box/a.out\"): No such file: or directory")
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_kept() {
+        let metadata: IsolateMetadata = "time:1.5\ncg-oom-killed:1\nfuture-key:a:b\n"
+            .parse()
+            .unwrap();
+
+        assert!(metadata.cg_oom_killed);
+        assert_eq!(metadata.extra.len(), 1);
+        assert_eq!(metadata.extra["future-key"], "a:b");
+    }
+
+    #[test]
+    fn blank_lines_are_skipped() {
+        let metadata: IsolateMetadata = "\ntime:0.5\n   \n\nexitsig:9\nkilled:1\n\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(metadata.time, Some(0.5));
+        assert_eq!(metadata.exit_signal, Some(9));
+        assert!(metadata.killed);
+    }
+
+    #[test]
+    fn malformed_value() {
+        let error = "time:0.5\n\nmax-rss:lots\n"
+            .parse::<IsolateMetadata>()
+            .unwrap_err();
+
+        assert_eq!(error.line, 3);
+        assert_eq!(error.reason, "invalid value 'lots' for key 'max-rss'");
+    }
+
+    #[test]
+    fn missing_separator() {
+        let error = "time:0.5\ngarbage\n"
+            .parse::<IsolateMetadata>()
+            .unwrap_err();
+
+        assert_eq!(error.line, 2);
+        assert_eq!(error.reason, "missing ':' in 'garbage'");
+    }
+
+    #[test]
+    fn empty_metadata() {
+        let metadata: IsolateMetadata = "".parse().unwrap();
+
+        assert_eq!(metadata.time, None);
+        assert!(metadata.extra.is_empty());
+    }
+
+    #[test]
+    fn environment_values_are_passed_as_is() {
+        let environment = [
+            ("QUOTES", r#"say "hi" 'there'"#),
+            ("BACKSLASHES", r"C:\path\n"),
+            ("SPACES", " a  b "),
+            ("EQUALS", "a=b=c"),
+            ("EMPTY", ""),
+            ("UNICODE", "é\n✓"),
+        ]
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+
+        assert_eq!(
+            environment_args(&environment).unwrap(),
+            vec![
+                r"-EBACKSLASHES=C:\path\n",
+                "-EEMPTY=",
+                "-EEQUALS=a=b=c",
+                r#"-EQUOTES=say "hi" 'there'"#,
+                "-ESPACES= a  b ",
+                "-EUNICODE=é\n✓",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_variables_are_never_passed() {
+        for (key, value) in [("A=B", "x"), ("", "x"), ("A\0", "x"), ("A", "a\0b")] {
+            let environment = vec![(key.to_string(), value.to_string())]
+                .into_iter()
+                .collect();
+
+            assert!(environment_args(&environment).is_err(), "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn base_environment_is_completed_by_the_administrator() {
+        let default = base_environment_with(None);
+        assert_eq!(default.len(), 2);
+        assert_eq!(default["HOME"], "/tmp");
+
+        let environment = base_environment_with(Some(
+            r#"{"HOME": "/home/box", "LANG": "C.UTF-8", "1BAD": "x", "NUL": "a\u0000"}"#,
+        ));
+        assert_eq!(environment["HOME"], "/home/box");
+        assert_eq!(environment["LANG"], "C.UTF-8");
+        assert_eq!(environment["PATH"], default["PATH"]);
+        assert_eq!(environment.len(), 3);
+
+        // Malformed values are ignored
+        assert_eq!(base_environment_with(Some("[1]")), default);
     }
 }
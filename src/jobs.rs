@@ -0,0 +1,283 @@
+use crate::api_helpers::{ApiError, ApiErrorCode};
+use crate::auth::Client;
+use crate::scheduler;
+use actix_web::http::StatusCode;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A run in progress, identified by its request ID.
+pub struct Job {
+    pub id: String,
+    client: String,
+    cancelled: AtomicBool,
+    /// PID of the isolate process currently running in the job's box
+    process: Mutex<Option<u32>>,
+    /// Whether the last tracked process was interrupted
+    interrupted: AtomicBool,
+}
+
+impl Job {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Whether the last command was interrupted by a cancellation. Cancelling a job after its
+    /// command exited doesn't change the command's outcome.
+    pub fn was_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+
+    fn interrupt(&self, pid: u32) {
+        // isolate kills the box's processes when interrupted
+        if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+            tracing::warn!(
+                "Failed to interrupt process {}: {}",
+                pid,
+                std::io::Error::last_os_error()
+            );
+
+            return;
+        }
+
+        self.interrupted.store(true, Ordering::SeqCst);
+    }
+
+    /// Interrupts the running command, and prevents the next ones from running.
+    pub fn cancel(&self) {
+        let process = self.process.lock().unwrap();
+
+        if self.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        tracing::info!(job_id = %self.id, "Job cancelled");
+
+        if let Some(pid) = *process {
+            self.interrupt(pid);
+        }
+    }
+
+    /// Tracks the process running in the job's box, interrupted right away if the job is already cancelled.
+    pub fn set_process(&self, pid: Option<u32>) {
+        let mut process = self.process.lock().unwrap();
+        *process = pid;
+
+        if let Some(pid) = pid {
+            self.interrupted.store(false, Ordering::SeqCst);
+
+            if self.is_cancelled() {
+                self.interrupt(pid);
+            }
+        }
+    }
+}
+
+lazy_static! {
+    /// Jobs by client ID and job ID, clients can reuse each other's IDs
+    static ref JOBS: Mutex<HashMap<(String, String), Arc<Job>>> = Mutex::new(HashMap::new());
+}
+
+/// Registration of a job, removed from the registry once dropped. The job is cancelled if the
+/// handle is dropped before completion, e.g. when the client disconnects.
+pub struct JobHandle {
+    job: Arc<Job>,
+    completed: bool,
+}
+
+impl JobHandle {
+    pub fn job(&self) -> Arc<Job> {
+        self.job.clone()
+    }
+
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Deref for JobHandle {
+    type Target = Job;
+
+    fn deref(&self) -> &Job {
+        &self.job
+    }
+}
+
+impl Drop for JobHandle {
+    fn drop(&mut self) {
+        JOBS.lock()
+            .unwrap()
+            .remove(&(self.job.client.clone(), self.job.id.clone()));
+
+        if !self.completed {
+            self.job.cancel();
+        }
+    }
+}
+
+pub fn register(id: String, client: &Client) -> Result<JobHandle, ApiError> {
+    let mut jobs = JOBS.lock().unwrap();
+    let key = (client.id(), id.clone());
+
+    if jobs.contains_key(&key) {
+        return ApiError::new(
+            StatusCode::CONFLICT,
+            ApiErrorCode::JobAlreadyExists,
+            format!("A job with the ID '{}' is already in progress", id),
+        )
+        .into();
+    }
+
+    let job = Arc::new(Job {
+        id: id.clone(),
+        client: client.id(),
+        cancelled: AtomicBool::new(false),
+        process: Mutex::new(None),
+        interrupted: AtomicBool::new(false),
+    });
+
+    jobs.insert(key, job.clone());
+
+    Ok(JobHandle {
+        job,
+        completed: false,
+    })
+}
+
+/// Cancels a job of the client, queued or running.
+pub fn cancel(id: &str, client: &Client) -> Result<(), ApiError> {
+    let client_id = client.id();
+    let job = JOBS
+        .lock()
+        .unwrap()
+        .get(&(client_id.clone(), id.to_string()))
+        .cloned();
+
+    match job {
+        Some(job) => {
+            job.cancel();
+            scheduler::dequeue(&client_id, id);
+
+            Ok(())
+        }
+        None => ApiError::not_found(
+            ApiErrorCode::JobNotFound,
+            format!("No job with the ID '{}' is in progress", id),
+        )
+        .into(),
+    }
+}
+
+/// Error of the runs cancelled before their first phase
+pub fn cancelled_error() -> ApiError {
+    ApiError::new(
+        StatusCode::CONFLICT,
+        ApiErrorCode::Cancelled,
+        "The run was cancelled",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::Priority;
+    use std::future::Future;
+    use std::task::{Context, Poll, Waker};
+
+    fn client(name: &str) -> Client {
+        Client {
+            name: Some(format!("jobs-tests-{}", name)),
+            address: None,
+            policy: Default::default(),
+        }
+    }
+
+    fn is_registered(id: &str, client: &Client) -> bool {
+        JOBS.lock()
+            .unwrap()
+            .contains_key(&(client.id(), id.to_string()))
+    }
+
+    #[test]
+    fn job_ids_are_unique_per_client() {
+        let (client, other_client) = (client("unique"), client("unique-other"));
+        let _job = register("job".into(), &client).unwrap();
+
+        let error = register("job".into(), &client).err().unwrap();
+
+        assert_eq!(error.code, ApiErrorCode::JobAlreadyExists);
+        assert_eq!(error.status, StatusCode::CONFLICT);
+        assert!(register("job".into(), &other_client).is_ok());
+    }
+
+    #[test]
+    fn dropping_the_handle_unregisters_the_job() {
+        let client = client("drop");
+
+        let completed = register("completed".into(), &client).unwrap();
+        let arc = completed.job();
+        completed.complete();
+
+        assert!(!is_registered("completed", &client));
+        assert!(!arc.is_cancelled());
+
+        // Dropped before completion, e.g. the client disconnected
+        let disconnected = register("disconnected".into(), &client).unwrap();
+        let arc = disconnected.job();
+        drop(disconnected);
+
+        assert!(!is_registered("disconnected", &client));
+        assert!(arc.is_cancelled());
+        assert!(register("disconnected".into(), &client).is_ok());
+    }
+
+    #[test]
+    fn unknown_jobs_can_not_be_cancelled() {
+        let (client, other_client) = (client("unknown"), client("unknown-other"));
+        let job = register("job".into(), &client).unwrap();
+
+        let error = cancel("missing", &client).unwrap_err();
+
+        assert_eq!(error.code, ApiErrorCode::JobNotFound);
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+        // Other clients can't cancel the job
+        assert!(cancel("job", &other_client).is_err());
+        assert!(!job.is_cancelled());
+    }
+
+    #[test]
+    fn cancelled_queued_jobs_never_start() {
+        let client = client("queued");
+        let mut context = Context::from_waker(Waker::noop());
+        let mut handles = vec![];
+        let mut permits = vec![];
+
+        // Takes every slot until a job has to wait in the queue
+        let queued = loop {
+            let handle = register(format!("job-{}", handles.len()), &client).unwrap();
+            let permit = Box::pin(scheduler::schedule(&client, Priority::default(), &handle))
+                .as_mut()
+                .poll(&mut context);
+
+            match permit {
+                Poll::Ready(permit) => permits.push(permit.unwrap()),
+                Poll::Pending => break handle,
+            }
+
+            handles.push(handle);
+        };
+        let id = queued.id.clone();
+        let mut schedule = Box::pin(scheduler::schedule(&client, Priority::default(), &queued));
+
+        assert!(schedule.as_mut().poll(&mut context).is_pending());
+
+        cancel(&id, &client).unwrap();
+
+        assert!(matches!(
+            schedule.as_mut().poll(&mut context),
+            Poll::Ready(None)
+        ));
+        assert!(is_registered(&id, &client));
+    }
+}
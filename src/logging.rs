@@ -0,0 +1,220 @@
+use crate::api_helpers::{ApiError, ApiErrorCode};
+use crate::{telemetry, utils};
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use rand::{thread_rng, Rng};
+use std::future::{ready, Future, Ready};
+use std::time::Instant;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::{EnvFilter, Targets};
+use tracing_subscriber::prelude::*;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const REDACTED: &str = "[redacted]";
+
+/// JSON logs on stdout, one object per line. The level is read from `LOG_LEVEL`
+/// and accepts `RUST_LOG` like directives (e.g. `info,godbox=debug`).
+/// godbox's spans are also exported to `tracer_provider` regardless of the log level.
+pub fn init(tracer_provider: Option<&SdkTracerProvider>) {
+    let level = utils::parsed_env::get("LOG_LEVEL", String::from("info"));
+
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .json()
+        .with_current_span(false)
+        .with_span_list(true)
+        .with_filter(EnvFilter::new(level));
+
+    let telemetry_layer = tracer_provider.map(|provider| {
+        telemetry::layer(provider)
+            .with_filter(Targets::new().with_target("godbox", tracing::Level::INFO))
+    });
+
+    tracing_subscriber::registry()
+        .with(fmt_layer)
+        .with(telemetry_layer)
+        .init();
+}
+
+fn redact_scripts() -> bool {
+    utils::parsed_env::get_bool("LOG_REDACT_SCRIPTS", true)
+}
+
+fn redact_environment() -> bool {
+    utils::parsed_env::get_bool("LOG_REDACT_ENVIRONMENT", true)
+}
+
+/// Script content as it can be logged, following `LOG_REDACT_SCRIPTS`.
+pub fn script(content: &str) -> &str {
+    match redact_scripts() {
+        true => REDACTED,
+        false => content,
+    }
+}
+
+/// Command line as it can be logged. Following `LOG_REDACT_ENVIRONMENT`, values of `-E` arguments
+/// are hidden. Following `LOG_REDACT_SCRIPTS`, arguments of the program run inside the box are hidden.
+pub fn command_line(args: &[String]) -> String {
+    redacted_command_line(args, redact_environment(), redact_scripts())
+}
+
+fn redacted_command_line(
+    args: &[String],
+    redact_environment: bool,
+    redact_scripts: bool,
+) -> String {
+    let mut in_box_command = false;
+    let mut in_box_arguments = false;
+
+    args.iter()
+        .map(|arg| {
+            if in_box_arguments && redact_scripts {
+                return REDACTED.into();
+            }
+
+            if in_box_command {
+                in_box_arguments = true;
+            } else if arg == "--" {
+                in_box_command = true;
+            } else if let (true, Some(variable)) = (redact_environment, arg.strip_prefix("-E")) {
+                if let Some((key, _)) = variable.split_once('=') {
+                    return format!("-E{}={}", key, REDACTED);
+                }
+            }
+
+            arg.clone()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Accepts a client provided ID if it is reasonably short and printable.
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            !value.is_empty() && value.len() <= 128 && value.chars().all(|c| c.is_ascii_graphic())
+        })
+        .map(Into::into)
+        .unwrap_or_else(|| format!("{:032x}", thread_rng().gen::<u128>()))
+}
+
+/// ID of the current request, given by [`middleware`]
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl FromRequest for RequestId {
+    type Error = ApiError;
+    type Future = Ready<Result<RequestId, ApiError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(match req.extensions().get::<RequestId>() {
+            Some(request_id) => Ok(request_id.clone()),
+            None => ApiError::internal_server_error(
+                ApiErrorCode::InternalError,
+                "The request has no ID",
+            )
+            .into(),
+        })
+    }
+}
+
+/// Middleware running every request in a span holding its ID, and returning the ID
+/// in the `X-Request-Id` header.
+pub fn middleware<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    let request_id = request_id(&req);
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.path(),
+        client = tracing::field::Empty
+    );
+
+    // Continue the client's trace when a `traceparent` header is given
+    let _ = span.set_parent(telemetry::parent_context(req.headers()));
+
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let start = Instant::now();
+    let response = span.in_scope(|| srv.call(req));
+
+    async move {
+        let mut response = response.await?;
+
+        tracing::info!(
+            status = response.status().as_u16(),
+            duration = start.elapsed().as_secs_f64(),
+            "Request completed"
+        );
+
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+
+        Ok(response)
+    }
+    .instrument(span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command_line: &str) -> Vec<String> {
+        command_line.split(' ').map(Into::into).collect()
+    }
+
+    const RUN: &str = "isolate -b1 -ESECRET=hunter2 -EPATH=/usr/bin -EEMPTY= -E=x -EINHERITED --run -- /usr/bin/python3 script.py --token=abc";
+
+    #[test]
+    fn everything_is_redacted_by_default() {
+        assert_eq!(
+            redacted_command_line(&args(RUN), true, true),
+            "isolate -b1 -ESECRET=[redacted] -EPATH=[redacted] -EEMPTY=[redacted] -E=[redacted] -EINHERITED --run -- /usr/bin/python3 [redacted] [redacted]"
+        );
+    }
+
+    #[test]
+    fn environment_redaction_can_be_disabled() {
+        assert_eq!(
+            redacted_command_line(&args(RUN), false, true),
+            "isolate -b1 -ESECRET=hunter2 -EPATH=/usr/bin -EEMPTY= -E=x -EINHERITED --run -- /usr/bin/python3 [redacted] [redacted]"
+        );
+    }
+
+    #[test]
+    fn script_redaction_can_be_disabled() {
+        assert_eq!(
+            redacted_command_line(&args(RUN), true, false),
+            "isolate -b1 -ESECRET=[redacted] -EPATH=[redacted] -EEMPTY=[redacted] -E=[redacted] -EINHERITED --run -- /usr/bin/python3 script.py --token=abc"
+        );
+        assert_eq!(redacted_command_line(&args(RUN), false, false), RUN);
+    }
+
+    #[test]
+    fn only_isolate_arguments_are_read_as_variables() {
+        // Arguments of the program are never `-E` options, even without script redaction
+        assert_eq!(
+            redacted_command_line(&args("isolate --run -- /bin/env -EKEY=value"), true, false),
+            "isolate --run -- /bin/env -EKEY=value"
+        );
+        assert_eq!(
+            redacted_command_line(&args("isolate -b1 --cleanup"), true, true),
+            "isolate -b1 --cleanup"
+        );
+    }
+}
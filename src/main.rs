@@ -1,70 +1,103 @@
-use actix_web::{error::InternalError, App, HttpResponse, HttpServer};
-use actix_web_validator::{Error, JsonConfig};
-use serde::Serialize;
+use actix_web::dev::Service;
+use actix_web::{web::PayloadConfig, App, HttpServer};
+use std::time::Instant;
 use std::{env, io};
-use validator::ValidationErrors;
 
 extern crate derive_more;
 
 #[macro_use]
 extern crate derive_builder;
 
-mod utils;
+#[macro_use]
+extern crate lazy_static;
+
 mod api_helpers;
+mod auth;
+mod health;
 mod isolate;
+mod jobs;
+mod logging;
+mod metrics;
 mod routes;
 mod runner;
+mod scheduler;
+mod telemetry;
+mod usage;
+mod utils;
 
-#[derive(Serialize)]
-pub struct ValidationErrorDTO {
-    pub message: String,
-    pub fields: Vec<String>,
-}
+#[actix_web::main]
+async fn main() -> io::Result<()> {
+    let tracer_provider = telemetry::tracer_provider();
+
+    logging::init(tracer_provider.as_ref().ok().and_then(Option::as_ref));
+
+    // Only logged once the logger is set up
+    let tracer_provider = tracer_provider.unwrap_or_else(|e| {
+        tracing::warn!("{}", e);
+
+        None
+    });
+    metrics::init();
+
+    runner::phase_settings::check_environment();
 
-impl From<&ValidationErrors> for ValidationErrorDTO {
-    fn from(error: &ValidationErrors) -> Self {
-        ValidationErrorDTO {
-            message: "Validation error".to_owned(),
-            fields: error
-                .field_errors()
-                .iter()
-                .map(|(field, _)| field.to_string())
-                .collect(),
-        }
+    if let Err(e) = auth::init().and_then(|_| runner::mounts::init()) {
+        tracing::error!("{}", e);
+
+        return Err(e);
     }
-}
 
-#[actix_web::main]
-async fn main() -> io::Result<()> {
-    HttpServer::new(|| {
+    let result = HttpServer::new(|| {
         App::new()
-            .app_data(
-                JsonConfig::default()
-                    .limit(
-                        match env::var("API_MAX_PAYLOAD_SIZE")
-                            .ok()
-                            .and_then(|value| value.parse::<f64>().ok())
-                        {
-                            Some(value) => value.round() as usize,
-                            None => 32768,
-                        },
-                    )
-                    .error_handler(|err, _| {
-                        let json_error = match &err {
-                            Error::Validate(error) => ValidationErrorDTO::from(error),
-                            _ => ValidationErrorDTO {
-                                message: err.to_string(),
-                                fields: Vec::new(),
-                            },
-                        };
-
-                        InternalError::from_response(err, HttpResponse::Conflict().json(json_error))
-                            .into()
-                    }),
-            )
+            .wrap_fn(|req, srv| {
+                let start = Instant::now();
+                let response = srv.call(req);
+
+                async move {
+                    let response = response.await?;
+
+                    // Route patterns instead of paths to keep the labels' cardinality bounded
+                    let route = response
+                        .request()
+                        .match_pattern()
+                        .unwrap_or_else(|| "unmatched".into());
+
+                    metrics::HTTP_REQUESTS
+                        .with_label_values(&[&route, response.status().as_str()])
+                        .inc();
+                    metrics::HTTP_REQUEST_DURATION
+                        .with_label_values(&[&route])
+                        .observe(start.elapsed().as_secs_f64());
+
+                    Ok(response)
+                }
+            })
+            .wrap_fn(logging::middleware)
+            // Limits the size of the JSON bodies
+            .app_data(PayloadConfig::new(
+                match env::var("API_MAX_PAYLOAD_SIZE")
+                    .ok()
+                    .and_then(|value| value.parse::<f64>().ok())
+                {
+                    Some(value) => value.round() as usize,
+                    None => 32768,
+                },
+            ))
+            .service(routes::healthz_get::route)
+            .service(routes::readyz_get::route)
+            .service(routes::metrics_get::route)
+            .service(routes::run_plan_post::route)
             .service(routes::run_post::route)
+            .service(routes::usage_get::route)
+            .service(routes::jobs_delete::route)
     })
     .bind("0.0.0.0:8080")?
     .run()
-    .await
+    .await;
+
+    if let Some(tracer_provider) = tracer_provider {
+        telemetry::shutdown(tracer_provider);
+    }
+
+    result
 }
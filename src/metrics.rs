@@ -0,0 +1,124 @@
+use prometheus::{
+    register_histogram_vec_with_registry, register_histogram_with_registry,
+    register_int_counter_vec_with_registry, register_int_counter_with_registry,
+    register_int_gauge_vec_with_registry, register_int_gauge_with_registry, Encoder, Histogram,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Registry, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new_custom(Some("godbox".into()), None).unwrap();
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec_with_registry!(
+        "http_requests_total",
+        "HTTP requests by route and status",
+        &["route", "status"],
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec_with_registry!(
+        "http_request_duration_seconds",
+        "HTTP request duration by route",
+        &["route"],
+        vec![0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0],
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref PHASES: IntCounterVec = register_int_counter_vec_with_registry!(
+        "phases_total",
+        "Executed phases by verdict and language",
+        &["verdict", "language"],
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref PHASE_TIME: HistogramVec = register_histogram_vec_with_registry!(
+        "phase_time_seconds",
+        "CPU time of the phases",
+        &["language"],
+        vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0],
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref PHASE_WALL_TIME: HistogramVec = register_histogram_vec_with_registry!(
+        "phase_wall_time_seconds",
+        "Wall-clock time of the phases",
+        &["language"],
+        vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0],
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref PHASE_MEMORY: HistogramVec = register_histogram_vec_with_registry!(
+        "phase_memory_kilobytes",
+        "Peak memory of the phases' control group",
+        &["language"],
+        prometheus::exponential_buckets(1024.0, 2.0, 11).unwrap(),
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref BOX_INIT_DURATION: Histogram = register_histogram_with_registry!(
+        "box_init_duration_seconds",
+        "Time taken to initialize a box",
+        vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5],
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref BOX_INIT_FAILURES: IntCounter = register_int_counter_with_registry!(
+        "box_init_failures_total",
+        "Boxes that failed to initialize",
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref BOX_CLEANUP_DURATION: Histogram = register_histogram_with_registry!(
+        "box_cleanup_duration_seconds",
+        "Time taken to cleanup a box",
+        vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5],
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref BOX_CLEANUP_FAILURES: IntCounter = register_int_counter_with_registry!(
+        "box_cleanup_failures_total",
+        "Boxes that failed to cleanup",
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref ACTIVE_BOXES: IntGauge =
+        register_int_gauge_with_registry!("active_boxes", "Boxes currently initialized", REGISTRY)
+            .unwrap();
+    pub static ref QUEUED_RUNS: IntGaugeVec = register_int_gauge_vec_with_registry!(
+        "queued_runs",
+        "Runs waiting for a free slot by priority",
+        &["priority"],
+        REGISTRY
+    )
+    .unwrap();
+    pub static ref QUEUE_WAIT_DURATION: HistogramVec = register_histogram_vec_with_registry!(
+        "queue_wait_duration_seconds",
+        "Time spent by the runs waiting for a free slot",
+        &["priority"],
+        vec![0.0, 0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0],
+        REGISTRY
+    )
+    .unwrap();
+}
+
+/// Registers the metrics without labels so they are exposed before their first update
+pub fn init() {
+    lazy_static::initialize(&BOX_INIT_DURATION);
+    lazy_static::initialize(&BOX_INIT_FAILURES);
+    lazy_static::initialize(&BOX_CLEANUP_DURATION);
+    lazy_static::initialize(&BOX_CLEANUP_FAILURES);
+    lazy_static::initialize(&ACTIVE_BOXES);
+
+    for priority in &["batch", "normal", "interactive"] {
+        QUEUED_RUNS.with_label_values(&[priority]);
+    }
+}
+
+/// Every metric in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = vec![];
+
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        tracing::error!("Failed to encode metrics: {}", e);
+    }
+
+    String::from_utf8(buffer).unwrap_or_default()
+}
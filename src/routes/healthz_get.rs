@@ -0,0 +1,13 @@
+use actix_web::{get, web::Json};
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub struct HealthResponseDTO {
+    status: &'static str,
+}
+
+/// Liveness probe, only tells that the process is able to answer.
+#[get("/healthz")]
+pub async fn route() -> Json<HealthResponseDTO> {
+    Json(HealthResponseDTO { status: "ok" })
+}
@@ -0,0 +1,26 @@
+use crate::api_helpers::ApiResult;
+use crate::auth::Client;
+use crate::jobs;
+use actix_web::{delete, web::Json, web::Path};
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub struct CancelledJobDTO {
+    id: String,
+    status: &'static str,
+}
+
+/// Cancels a queued or running job of the client. Its ID is the request ID of the run.
+#[delete("/jobs/{id}")]
+pub async fn route(client: Client, id: Path<String>) -> ApiResult<CancelledJobDTO> {
+    let id = id.into_inner();
+
+    if let Err(e) = jobs::cancel(&id, &client) {
+        return e.into();
+    }
+
+    Ok(Json(CancelledJobDTO {
+        id,
+        status: "cancelled",
+    }))
+}
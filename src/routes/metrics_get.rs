@@ -0,0 +1,11 @@
+use crate::metrics;
+use actix_web::{get, HttpResponse};
+use prometheus::TEXT_FORMAT;
+
+/// Metrics in the Prometheus text exposition format.
+#[get("/metrics")]
+pub async fn route() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(TEXT_FORMAT)
+        .body(metrics::render())
+}
@@ -1 +1,7 @@
+pub mod healthz_get;
+pub mod jobs_delete;
+pub mod metrics_get;
+pub mod readyz_get;
+pub mod run_plan_post;
 pub mod run_post;
+pub mod usage_get;
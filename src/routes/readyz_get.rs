@@ -0,0 +1,31 @@
+use crate::health::{self, ReadinessCheck};
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub struct ReadinessResponseDTO {
+    status: &'static str,
+    checks: Vec<ReadinessCheck>,
+}
+
+/// Readiness probe, checks that boxes can actually be run. Answers `503` if any check fails.
+#[get("/readyz")]
+pub async fn route() -> HttpResponse {
+    // The checks run isolate, keep them off the server's threads
+    let checks = web::block(|| Ok::<_, ()>(health::cached_readiness_checks()))
+        .await
+        .ok();
+
+    match checks {
+        Some(checks) if checks.iter().all(|check| check.ok) => {
+            HttpResponse::Ok().json(ReadinessResponseDTO {
+                status: "ok",
+                checks,
+            })
+        }
+        checks => HttpResponse::ServiceUnavailable().json(ReadinessResponseDTO {
+            status: "unavailable",
+            checks: checks.unwrap_or_default(),
+        }),
+    }
+}
@@ -0,0 +1,52 @@
+use crate::api_helpers::{ApiResult, JsonBody};
+use crate::auth::RateLimitedClient;
+use crate::isolate::IsolatedBoxOptions;
+use crate::routes::run_post::{check_body, RunBodyDTO};
+use crate::runner::phase_settings::EffectivePhaseSettings;
+use actix_web::{post, web::Json};
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub struct PlannedPhaseDTO {
+    name: Option<String>,
+    settings: EffectivePhaseSettings,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PlanResponseDTO {
+    disk_quota_blocks: Option<u64>,
+    disk_quota_inodes: Option<u64>,
+    phases: Vec<PlannedPhaseDTO>,
+}
+
+/// Validates a run body and returns the settings each phase would run with, without creating a box.
+#[post("/run/plan")]
+pub async fn route(
+    RateLimitedClient(client): RateLimitedClient,
+    body: JsonBody<RunBodyDTO>,
+) -> ApiResult<PlanResponseDTO> {
+    if let Err(e) = check_body(&body, &client.policy) {
+        return e.into();
+    }
+
+    let init_options = body.init_options(&client.policy);
+
+    let phases = body
+        .resolve_phases(&client.policy)
+        .into_iter()
+        .map(|phase_settings| {
+            let options: IsolatedBoxOptions = phase_settings.clone().into();
+
+            PlannedPhaseDTO {
+                settings: EffectivePhaseSettings::new(&phase_settings, &options),
+                name: phase_settings.name,
+            }
+        })
+        .collect();
+
+    Ok(Json(PlanResponseDTO {
+        disk_quota_blocks: init_options.disk_quota.map(|(blocks, _)| blocks),
+        disk_quota_inodes: init_options.disk_quota.map(|(_, inodes)| inodes),
+        phases,
+    }))
+}
@@ -1,147 +1,409 @@
-use crate::api_helpers::{ApiError, ApiResult};
-use crate::runner::phase_settings::{PhaseSandboxSettings, PhaseSettings};
+use crate::api_helpers::{ApiError, ApiErrorCode, ApiResult, JsonBody, Violation};
+use crate::auth::{Policy, RateLimitedClient};
+use crate::isolate::IsolatedBoxInitOptions;
+use crate::jobs::{self, Job};
+use crate::logging::RequestId;
+use crate::runner::mounts;
+use crate::runner::phase_settings::{PhaseEnvironment, PhaseSandboxSettings, PhaseSettings};
 use crate::runner::runner::Runner;
 use crate::runner::runner::RunnerPhaseResult;
-use crate::utils;
-use actix_web::{post, web::Json};
+use crate::runner::verdict::PhaseVerdict;
+use crate::scheduler::{self, Priority};
+use crate::usage::{self, RunSlot};
+use crate::utils::{self, validators};
+use actix_web::error::BlockingError;
+use actix_web::{post, web, web::Json};
+use chrono::{DateTime, Utc};
 use merge::Merge;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
 use validator::Validate;
 
 #[derive(Deserialize, Debug, Validate)]
 pub struct RunBodyDTO {
+    #[validate(custom = "validators::validate_phase_count")]
+    #[validate]
     phases: Vec<PhaseSettings>,
 
-    environment: Option<HashMap<String, String>>,
+    /// Checked by `check_body`, which reports every invalid variable
+    environment: Option<PhaseEnvironment>,
     sandbox_settings: Option<PhaseSandboxSettings>,
 
+    /// Defaults to the client's policy priority
+    priority: Option<Priority>,
+
     files: String,
 }
 
+impl RunBodyDTO {
+    /// Phases with their default name, and the global environment and sandbox settings merged in.
+    /// Phase values take precedence over global ones, `null` environment values are kept so they
+    /// can unset base variables. Unset limits are capped by the policy's.
+    pub fn resolve_phases(&self, policy: &Policy) -> Vec<PhaseSettings> {
+        let mut phases = vec![];
+
+        for i in 0..self.phases.len() {
+            let mut phase_settings = self.phases[i].clone();
+
+            phase_settings.name = phase_settings.name.or(Some(i.to_string()));
+
+            if let Some(mut environment) = self.environment.clone() {
+                if let Some(phase_environment) = phase_settings.environment {
+                    environment.extend(phase_environment);
+                }
+
+                phase_settings.environment = Some(environment);
+            }
+
+            if let Some(sandbox_settings) = self.sandbox_settings.clone() {
+                if let Some(phase_sandbox_settings) = &mut phase_settings.sandbox_settings {
+                    phase_sandbox_settings.merge(sandbox_settings);
+                } else {
+                    phase_settings.sandbox_settings = Some(sandbox_settings);
+                }
+            }
+
+            phase_settings
+                .sandbox_settings
+                .get_or_insert_with(Default::default)
+                .cap_defaults(&policy.limits);
+
+            phases.push(phase_settings);
+        }
+
+        phases
+    }
+
+    pub fn init_options(&self, policy: &Policy) -> IsolatedBoxInitOptions {
+        let mut sandbox_settings = self.sandbox_settings.clone().unwrap_or_default();
+
+        sandbox_settings.cap_defaults(&policy.limits);
+
+        IsolatedBoxInitOptions::from(&sandbox_settings)
+    }
+}
+
+/// Durations are in seconds. The sandbox overhead is `total_duration` minus the phases' `time_wall`.
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct RunTimelineDTO {
+    started_at: DateTime<Utc>,
+    finished_at: DateTime<Utc>,
+    /// Time spent waiting for a free slot, not included in `total_duration`
+    queue_duration: f64,
+    setup_duration: f64,
+    phases_duration: f64,
+    teardown_duration: f64,
+    total_duration: f64,
+}
+
+/// Aggregate of the executed phases. Times are in seconds, memory in kilobytes.
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct RunSummaryDTO {
+    /// Verdict of the failing phase, `ok` when every phase succeeded
+    verdict: PhaseVerdict,
+    failed_phase_index: Option<usize>,
+    failed_phase_name: Option<String>,
+    executed_phases: usize,
+    skipped_phases: usize,
+    total_time: f64,
+    total_time_wall: f64,
+    peak_memory: Option<u64>,
+}
+
+impl RunSummaryDTO {
+    pub fn new(results: &[RunnerPhaseResult], phase_count: usize) -> RunSummaryDTO {
+        let failed_phase = results
+            .iter()
+            .enumerate()
+            .find(|(_, result)| result.status != 0 || result.verdict != PhaseVerdict::Ok);
+
+        RunSummaryDTO {
+            verdict: match failed_phase {
+                Some((_, result)) if result.verdict == PhaseVerdict::Ok => {
+                    PhaseVerdict::RuntimeError
+                }
+                Some((_, result)) => result.verdict,
+                None => PhaseVerdict::Ok,
+            },
+            failed_phase_index: failed_phase.map(|(i, _)| i),
+            failed_phase_name: failed_phase.and_then(|(_, result)| result.name.clone()),
+            executed_phases: results.len(),
+            skipped_phases: phase_count - results.len(),
+            total_time: results.iter().filter_map(|result| result.time).sum(),
+            total_time_wall: results.iter().filter_map(|result| result.time_wall).sum(),
+            peak_memory: results
+                .iter()
+                .filter_map(|result| result.cg_peak_memory.or(result.peak_rss))
+                .max(),
+        }
+    }
+}
+
 #[derive(Serialize, Debug, Default, Builder)]
 #[builder(setter(into, strip_option), default)]
 pub struct RunResponseDTO {
+    summary: RunSummaryDTO,
     phases: Vec<RunnerPhaseResult>,
+    timeline: RunTimelineDTO,
 }
 
-fn is_over_cap_limit_env(current_option: Option<u64>, name: &str) -> bool {
-    match current_option {
-        Some(current) => current > utils::parsed_env::get(name, u64::MAX),
-        None => false,
-    }
+/// The policy's maximum, or the administrator's one from the environment
+fn max_value<T>(policy_max: Option<T>, env_name: &str, unbounded: T) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    policy_max.unwrap_or_else(|| utils::parsed_env::get(env_name, unbounded))
 }
 
-fn check_body(body: &actix_web_validator::Json<RunBodyDTO>) -> Result<(), ApiError> {
-    fn setting_max_value_error(origin: &str, env_name: &str) -> ApiError {
-        ApiError::bad_request(format!(
-            "{}: maximum allowed value is {}",
-            origin,
-            env::var(env_name).unwrap_or(u64::MAX.to_string())
-        ))
-    }
+fn setting_max_value_violation<T>(field: String, value: T, max: T, unit: &str) -> Violation
+where
+    T: Display + Serialize,
+{
+    Violation::new(
+        ApiErrorCode::LimitExceeded,
+        field,
+        format!("maximum allowed value is {} {}", max, unit),
+    )
+    .with_max(max)
+    .with_value(value)
+}
 
+#[rustfmt::skip]
+fn check_sandbox_settings(origin: &str, sandbox_settings: &PhaseSandboxSettings, policy: &Policy, violations: &mut Vec<Violation>) {
     macro_rules! check_cap_limit {
-        ($origin_str:expr, $origin_expr:expr, $env_name:expr) => {
-            if is_over_cap_limit_env($origin_expr, $env_name) {
-                return setting_max_value_error($origin_str, $env_name).into();
+        ($field:ident, $env_name:expr, $unbounded:expr, $unit:expr) => {
+            if let Some(value) = sandbox_settings.$field {
+                let max = max_value(policy.limits.$field, $env_name, $unbounded);
+
+                if value > max {
+                    violations.push(setting_max_value_violation(format!("{}.{}", origin, stringify!($field)), value, max, $unit));
+                }
             }
         };
     }
 
+    check_cap_limit!(run_time_limit, "MAX_RUN_TIME_LIMIT", f64::MAX, "seconds");
+    check_cap_limit!(extra_time_limit, "MAX_EXTRA_TIME_LIMIT", f64::MAX, "seconds");
+    check_cap_limit!(wall_time_limit, "MAX_WALL_TIME_LIMIT", f64::MAX, "seconds");
+    check_cap_limit!(stack_size_limit, "MAX_STACK_SIZE_LIMIT", u64::MAX, "KB");
+    check_cap_limit!(process_count_limit, "MAX_PROCESS_COUNT_LIMIT", u64::MAX, "processes");
+    check_cap_limit!(memory_limit, "MAX_MEMORY_LIMIT", u64::MAX, "KB");
+    check_cap_limit!(storage_limit, "MAX_STORAGE_LIMIT", u64::MAX, "KB");
+    check_cap_limit!(open_files_limit, "MAX_OPEN_FILES_LIMIT", u64::MAX, "files");
+    check_cap_limit!(core_size_limit, "MAX_CORE_SIZE_LIMIT", u64::MAX, "KB");
+    check_cap_limit!(address_space_limit, "MAX_ADDRESS_SPACE_LIMIT", u64::MAX, "KB");
+    check_cap_limit!(disk_quota_blocks, "MAX_DISK_QUOTA_BLOCKS", u64::MAX, "blocks");
+    check_cap_limit!(disk_quota_inodes, "MAX_DISK_QUOTA_INODES", u64::MAX, "inodes");
+
+    if sandbox_settings.cg_timing.is_some() && !policy.allow_cg_timing_toggle() {
+        violations.push(Violation::new(ApiErrorCode::CgTimingToggleDisabled, format!("{}.cg_timing", origin), "toggling cg_timing is not allowed"));
+    }
+
+    if sandbox_settings.network == Some(true) && !policy.allow_network() {
+        violations.push(Violation::new(ApiErrorCode::NetworkDisabled, format!("{}.network", origin), "network access is not allowed"));
+    }
+
+    if let Some(mount_names) = &sandbox_settings.mounts {
+        let declared_mounts = mounts::declared_mounts();
+
+        for (i, name) in mount_names.iter().enumerate() {
+            if !declared_mounts.contains_key(name) {
+                violations.push(
+                    Violation::new(ApiErrorCode::UnknownMount, format!("{}.mounts[{}]", origin, i), format!("unknown mount '{}'", name))
+                        .with_value(name),
+                );
+            } else if !policy.allows_mount(name) {
+                violations.push(
+                    Violation::new(ApiErrorCode::MountNotAllowed, format!("{}.mounts[{}]", origin, i), format!("mount '{}' is not allowed", name))
+                        .with_value(name),
+                );
+            }
+        }
+    }
+}
+
+fn check_environment(
+    origin: &str,
+    environment: &PhaseEnvironment,
+    violations: &mut Vec<Violation>,
+) {
+    for (key, error) in validators::environment_errors(environment) {
+        let field = match key {
+            Some(key) => format!("{}.{}", origin, key),
+            None => origin.to_string(),
+        };
+
+        violations.push(Violation::from_validation_error(field, &error));
+    }
+}
+
+pub fn check_body(body: &RunBodyDTO, policy: &Policy) -> Result<(), ApiError> {
+    let mut violations = match body.validate() {
+        Ok(_) => vec![],
+        Err(e) => Violation::from_validation_errors(&e),
+    };
+
+    if let Some(environment) = &body.environment {
+        check_environment("environment", environment, &mut violations);
+    }
+
     if let Some(sandbox_settings) = &body.sandbox_settings {
-        #[cfg_attr(rustfmt, rustfmt_skip)]
+        check_sandbox_settings(
+            "sandbox_settings",
+            sandbox_settings,
+            policy,
+            &mut violations,
+        );
+
+        // Only blamed on the request when it sets one of them without a default for the other
+        let requested = (
+            sandbox_settings.disk_quota_blocks,
+            sandbox_settings.disk_quota_inodes,
+        );
+
+        if let ((Some(_), None) | (None, Some(_)), (Some(_), None) | (None, Some(_))) =
+            (requested, sandbox_settings.disk_quota())
         {
-            check_cap_limit!("sandbox_settings.run_time_limit", sandbox_settings.run_time_limit, "MAX_RUN_TIME_LIMIT");
-            check_cap_limit!("sandbox_settings.extra_time_limit", sandbox_settings.extra_time_limit, "MAX_EXTRA_TIME_LIMIT");
-            check_cap_limit!("sandbox_settings.wall_time_limit", sandbox_settings.wall_time_limit, "MAX_WALL_TIME_LIMIT");
-            check_cap_limit!("sandbox_settings.stack_size_limit", sandbox_settings.stack_size_limit, "MAX_STACK_SIZE_LIMIT");
-            check_cap_limit!("sandbox_settings.process_count_limit", sandbox_settings.process_count_limit, "MAX_PROCESS_COUNT_LIMIT");
-            check_cap_limit!("sandbox_settings.memory_limit", sandbox_settings.memory_limit, "MAX_MEMORY_LIMIT");
-            check_cap_limit!("sandbox_settings.storage_limit", sandbox_settings.storage_limit, "MAX_STORAGE_LIMIT");
+            violations.push(Violation::new(
+                ApiErrorCode::InvalidRequest,
+                "sandbox_settings",
+                "disk_quota_blocks and disk_quota_inodes must be set together",
+            ));
         }
     }
 
-    let allow_profiling = match env::var("ALLOW_PROFILING") {
-        Ok(value) => {
-            let lowercase_value = value.to_lowercase();
-            ["true", "yes"].iter().any(|&s| s == lowercase_value)
-        },
-        Err(_) => true,
-    };
+    if let Some(priority) = body.priority {
+        let max_priority = policy.max_priority();
+
+        if priority > max_priority {
+            violations.push(
+                Violation::new(
+                    ApiErrorCode::PriorityNotAllowed,
+                    "priority",
+                    format!("maximum allowed priority is {}", max_priority.as_str()),
+                )
+                .with_max(max_priority)
+                .with_value(priority),
+            );
+        }
+    }
+
+    let allow_profiling = policy.allow_profiling();
 
-    for i in 0..body.phases.len() {
-        let phase_settings = body.phases[i].clone();
+    for (i, phase_settings) in body.phases.iter().enumerate() {
+        let origin = format!("phases[{}]", i);
 
-        if let Some(profiling) = phase_settings.profiling {
-            if profiling && !allow_profiling {
-                return ApiError::bad_request("Profiling is not allowed").into();
+        match (&phase_settings.script, &phase_settings.argv) {
+            (Some(_), Some(_)) | (None, None) => violations.push(Violation::new(
+                ApiErrorCode::InvalidRequest,
+                origin.clone(),
+                "exactly one of script and argv must be set",
+            )),
+            (None, Some(argv)) if argv.is_empty() => violations.push(Violation::new(
+                ApiErrorCode::InvalidRequest,
+                format!("{}.argv", origin),
+                "must not be empty",
+            )),
+            // isolate would fail to find the program, which is not a sandbox error
+            (None, Some(argv)) if !argv[0].starts_with('/') => violations.push(Violation::new(
+                ApiErrorCode::InvalidRequest,
+                format!("{}.argv[0]", origin),
+                "must be an absolute path",
+            )),
+            (None, Some(_)) if phase_settings.interpreter.is_some() => {
+                violations.push(Violation::new(
+                    ApiErrorCode::InvalidRequest,
+                    format!("{}.interpreter", origin),
+                    "only allowed with script",
+                ))
             }
+            _ => {}
+        }
+
+        if let Some(environment) = &phase_settings.environment {
+            check_environment(
+                &format!("{}.environment", origin),
+                environment,
+                &mut violations,
+            );
         }
 
-        if let Some(sandbox_settings) = phase_settings.sandbox_settings {
-            #[cfg_attr(rustfmt, rustfmt_skip)]
+        if phase_settings.profiling == Some(true) && !allow_profiling {
+            violations.push(Violation::new(
+                ApiErrorCode::ProfilingDisabled,
+                format!("{}.profiling", origin),
+                "profiling is not allowed",
+            ));
+        }
+
+        if let Some(sandbox_settings) = &phase_settings.sandbox_settings {
+            let origin = format!("{}.sandbox_settings", origin);
+
+            check_sandbox_settings(&origin, sandbox_settings, policy, &mut violations);
+
+            if sandbox_settings.disk_quota_blocks.is_some()
+                || sandbox_settings.disk_quota_inodes.is_some()
             {
-                check_cap_limit!(&format!("phases[{}].sandbox_settings.run_time_limit", i), sandbox_settings.run_time_limit, "MAX_RUN_TIME_LIMIT");
-                check_cap_limit!(&format!("phases[{}].sandbox_settings.extra_time_limit", i), sandbox_settings.extra_time_limit, "MAX_EXTRA_TIME_LIMIT");
-                check_cap_limit!(&format!("phases[{}].sandbox_settings.wall_time_limit", i), sandbox_settings.wall_time_limit, "MAX_WALL_TIME_LIMIT");
-                check_cap_limit!(&format!("phases[{}].sandbox_settings.stack_size_limit", i), sandbox_settings.stack_size_limit, "MAX_STACK_SIZE_LIMIT");
-                check_cap_limit!(&format!("phases[{}].sandbox_settings.process_count_limit", i), sandbox_settings.process_count_limit, "MAX_PROCESS_COUNT_LIMIT");
-                check_cap_limit!(&format!("phases[{}].sandbox_settings.memory_limit", i), sandbox_settings.memory_limit, "MAX_MEMORY_LIMIT");
-                check_cap_limit!(&format!("phases[{}].sandbox_settings.storage_limit", i), sandbox_settings.storage_limit, "MAX_STORAGE_LIMIT");
+                violations.push(Violation::new(
+                    ApiErrorCode::InvalidRequest,
+                    origin,
+                    "the disk quota can only be set in the global sandbox_settings",
+                ));
             }
         }
     }
 
-    Ok(())
-}
+    Violation::sort(&mut violations);
 
-#[post("/run")]
-pub async fn route(body: actix_web_validator::Json<RunBodyDTO>) -> ApiResult<RunResponseDTO> {
-    if let Err(e) = check_body(&body) {
-        return e.into();
+    match violations.is_empty() {
+        true => Ok(()),
+        false => ApiError::validation(violations).into(),
     }
+}
 
-    let mut runner = match Runner::new() {
+/// Runs every phase in a new box, then cleans it up.
+fn execute(
+    body: &RunBodyDTO,
+    policy: &Policy,
+    job: Arc<Job>,
+    run_slot: &RunSlot,
+    queue_duration: f64,
+) -> Result<RunResponseDTO, ApiError> {
+    let started_at = Utc::now();
+    let start = Instant::now();
+
+    let mut runner = match Runner::new(job.clone()) {
         Ok(v) => v,
         Err(e) => {
-            return ApiError::internal_server_error(format!(
-                "Failed to initialize the isolated environment: {}",
-                e
-            ))
+            return ApiError::internal_server_error(
+                ApiErrorCode::InternalError,
+                format!("Failed to initialize the isolated environment: {}", e),
+            )
             .into();
         }
     };
 
     let mut results = vec![];
 
-    let isolated_box_id = match runner.setup(&body.files) {
+    let isolated_box_id = match runner.setup(&body.files, body.init_options(policy)) {
         Ok(v) => v,
+        Err(_) if job.is_cancelled() => return jobs::cancelled_error().into(),
         Err(e) => return e.into(),
     };
 
-    for i in 0..body.phases.len() {
-        let mut phase_settings = body.phases[i].clone();
+    if job.is_cancelled() {
+        runner.teardown(isolated_box_id)?;
 
-        phase_settings.name = phase_settings.name.or(Some(i.to_string()));
+        return jobs::cancelled_error().into();
+    }
 
-        if let Some(environment) = body.environment.clone() {
-            if let Some(phase_environment) = &mut phase_settings.environment {
-                phase_environment.extend(environment.into_iter());
-            } else {
-                phase_settings.environment = Some(environment);
-            }
-        }
-
-        if let Some(sandbox_settings) = body.sandbox_settings.clone() {
-            if let Some(phase_sandbox_settings) = &mut phase_settings.sandbox_settings {
-                phase_sandbox_settings.merge(sandbox_settings);
-            } else {
-                phase_settings.sandbox_settings = Some(sandbox_settings);
-            }
-        }
+    let setup_duration = start.elapsed().as_secs_f64();
 
+    for phase_settings in body.resolve_phases(policy) {
         let result = match runner.run_phase(isolated_box_id, &phase_settings) {
             Ok(v) => v,
             Err(e) => return e.into(),
@@ -149,12 +411,307 @@ pub async fn route(body: actix_web_validator::Json<RunBodyDTO>) -> ApiResult<Run
 
         let status = result.status;
 
+        if let Some(time) = result.time {
+            run_slot.record_cpu_time(time);
+        }
+
         results.push(result);
 
-        if status != 0 {
+        if status != 0 || job.is_cancelled() {
             break;
         }
     }
 
-    Ok(Json(RunResponseDTO { phases: results }))
+    let phases_duration = start.elapsed().as_secs_f64() - setup_duration;
+
+    if let Err(e) = runner.teardown(isolated_box_id) {
+        return e.into();
+    }
+
+    let total_duration = start.elapsed().as_secs_f64();
+
+    Ok(RunResponseDTO {
+        summary: RunSummaryDTO::new(&results, body.phases.len()),
+        phases: results,
+        timeline: RunTimelineDTO {
+            started_at,
+            finished_at: Utc::now(),
+            queue_duration,
+            setup_duration,
+            phases_duration,
+            teardown_duration: total_duration - setup_duration - phases_duration,
+            total_duration,
+        },
+    })
+}
+
+#[post("/run")]
+pub async fn route(
+    request_id: RequestId,
+    RateLimitedClient(client): RateLimitedClient,
+    body: JsonBody<RunBodyDTO>,
+) -> ApiResult<RunResponseDTO> {
+    if let Err(e) = check_body(&body, &client.policy) {
+        return e.into();
+    }
+
+    // Only kept in the quota once the run leaves the queue
+    let mut run_slot = match usage::acquire_run_slot(&client) {
+        Ok(v) => v,
+        Err(e) => return e.into(),
+    };
+
+    // The run can be cancelled with its request ID, or by disconnecting
+    let job = match jobs::register(request_id.0, &client) {
+        Ok(v) => v,
+        Err(e) => return e.into(),
+    };
+
+    let priority = body.priority.unwrap_or_else(|| client.policy.priority());
+
+    let queued_at = Instant::now();
+    let permit = match scheduler::schedule(&client, priority, &job).await {
+        Some(v) => v,
+        None => return jobs::cancelled_error().into(),
+    };
+    let queue_duration = queued_at.elapsed().as_secs_f64();
+
+    run_slot.start();
+
+    // Runs block until they finish, keep them off the server's threads
+    let body = body.into_inner();
+    let span = tracing::Span::current();
+    let runner_job = job.job();
+    let policy = client.policy.clone();
+
+    let result = web::block(move || {
+        let _permit = permit;
+
+        span.in_scope(|| execute(&body, &policy, runner_job, &run_slot, queue_duration))
+    })
+    .await;
+
+    job.complete();
+
+    match result {
+        Ok(response) => Ok(Json(response)),
+        Err(BlockingError::Error(e)) => e.into(),
+        Err(BlockingError::Canceled) => ApiError::internal_server_error(
+            ApiErrorCode::InternalError,
+            "The run was canceled before completion",
+        )
+        .into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::isolate::IsolatedBoxOptions;
+    use crate::runner::phase_settings::EffectivePhaseSettings;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn body(value: serde_json::Value) -> RunBodyDTO {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn fields(violations: &[Violation]) -> Vec<&str> {
+        violations.iter().map(|v| v.field.as_str()).collect()
+    }
+
+    #[test]
+    fn validation_errors_of_nested_phases() {
+        let mut phases = vec![json!({"script": "true"}); 11];
+        phases[1] = json!({"name": "a\nb", "script": "true", "cwd": "a\u{0}"});
+        phases[10] = json!({"script": "true", "stdin": "x".repeat(65537)});
+
+        let errors = body(json!({"phases": phases, "files": ""}))
+            .validate()
+            .unwrap_err();
+        let mut violations = Violation::from_validation_errors(&errors);
+        Violation::sort(&mut violations);
+
+        assert_eq!(
+            fields(&violations),
+            vec!["phases[1].cwd", "phases[1].name", "phases[10].stdin"]
+        );
+
+        let stdin = &violations[2];
+        assert_eq!(stdin.code, ApiErrorCode::LimitExceeded);
+        assert_eq!(stdin.max, Some(json!(65536)));
+        assert_eq!(stdin.value, Some(json!(65537)));
+    }
+
+    #[test]
+    fn every_invalid_variable_is_reported() {
+        let body = body(json!({
+            "phases": [
+                {"script": "true"},
+                {"script": "true", "environment": {"OK": "1", "2BAD": "x", "NUL": "a\u{0}b"}},
+            ],
+            "environment": {"1BAD": "x", "A-B": null, "GOOD": "x"},
+            "priority": "interactive",
+            "files": "",
+        }));
+
+        let error = check_body(&body, &Policy::default()).unwrap_err();
+
+        assert_eq!(
+            fields(&error.violations),
+            vec![
+                "environment.1BAD",
+                "environment.A-B",
+                "phases[1].environment.2BAD",
+                "phases[1].environment.NUL",
+            ]
+        );
+    }
+
+    fn environments(body: &RunBodyDTO) -> Vec<HashMap<String, String>> {
+        body.resolve_phases(&Policy::default())
+            .into_iter()
+            .map(|phase_settings| IsolatedBoxOptions::from(phase_settings).environment)
+            .collect()
+    }
+
+    fn environment(variables: &[(&str, &str)]) -> HashMap<String, String> {
+        variables
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn phase_variables_override_global_and_base_ones() {
+        let body = body(json!({
+            "phases": [
+                {"script": "true", "environment": {"A": "phase", "B": null, "C": "phase"}},
+                {"script": "true"},
+            ],
+            "environment": {"HOME": "/global", "A": "global", "B": "global", "PATH": null},
+            "files": "",
+        }));
+
+        let environments = environments(&body);
+
+        assert_eq!(
+            environments[0],
+            environment(&[("HOME", "/global"), ("A", "phase"), ("C", "phase")])
+        );
+        assert_eq!(
+            environments[1],
+            environment(&[("HOME", "/global"), ("A", "global"), ("B", "global")])
+        );
+    }
+
+    #[test]
+    fn phases_can_unset_base_variables() {
+        let body = body(json!({
+            "phases": [
+                {"script": "true", "environment": {"PATH": null, "HOME": "/box"}},
+                {"script": "true"},
+            ],
+            "files": "",
+        }));
+
+        let environments = environments(&body);
+
+        // Without `BASE_ENVIRONMENT`
+        assert_eq!(environments[0], environment(&[("HOME", "/box")]));
+        assert_eq!(environments[1]["HOME"], "/tmp");
+        assert!(environments[1]["PATH"].ends_with(":/bin"));
+    }
+
+    fn phase_result(name: &str, status: i32, verdict: PhaseVerdict) -> RunnerPhaseResult {
+        let settings: PhaseSettings = serde_json::from_value(json!({"script": "true"})).unwrap();
+        let options = IsolatedBoxOptions::from(settings.clone());
+
+        RunnerPhaseResult {
+            name: Some(name.into()),
+            status,
+            stdout: String::new(),
+            stderr: String::new(),
+            time: None,
+            time_wall: None,
+            used_memory: None,
+            peak_rss: None,
+            cg_peak_memory: None,
+            oom_killed: false,
+            sandbox_status: None,
+            verdict,
+            signal: None,
+            signal_name: None,
+            csw_voluntary: None,
+            csw_forced: None,
+            started_at: Utc::now(),
+            finished_at: Utc::now(),
+            duration: 0.0,
+            settings: EffectivePhaseSettings::new(&settings, &options),
+        }
+    }
+
+    #[test]
+    fn summary_of_a_failed_run() {
+        let results = vec![
+            RunnerPhaseResult {
+                time: Some(0.25),
+                time_wall: Some(0.5),
+                cg_peak_memory: Some(2048),
+                peak_rss: Some(1024),
+                ..phase_result("compile", 0, PhaseVerdict::Ok)
+            },
+            RunnerPhaseResult {
+                time: Some(1.0),
+                time_wall: Some(1.25),
+                // Without cgroup measure, the RSS is the peak
+                peak_rss: Some(4096),
+                ..phase_result("test", 137, PhaseVerdict::MemoryLimit)
+            },
+        ];
+
+        let summary = RunSummaryDTO::new(&results, 4);
+
+        assert_eq!(summary.verdict, PhaseVerdict::MemoryLimit);
+        assert_eq!(summary.failed_phase_index, Some(1));
+        assert_eq!(summary.failed_phase_name.as_deref(), Some("test"));
+        assert_eq!(summary.executed_phases, 2);
+        assert_eq!(summary.skipped_phases, 2);
+        assert_eq!(summary.total_time, 1.25);
+        assert_eq!(summary.total_time_wall, 1.75);
+        assert_eq!(summary.peak_memory, Some(4096));
+    }
+
+    #[test]
+    fn summary_of_a_successful_run() {
+        let results = vec![
+            phase_result("a", 0, PhaseVerdict::Ok),
+            phase_result("b", 0, PhaseVerdict::Ok),
+        ];
+
+        let summary = RunSummaryDTO::new(&results, 2);
+
+        assert_eq!(summary.verdict, PhaseVerdict::Ok);
+        assert_eq!(summary.failed_phase_index, None);
+        assert_eq!(summary.failed_phase_name, None);
+        assert_eq!(summary.executed_phases, 2);
+        assert_eq!(summary.skipped_phases, 0);
+        assert_eq!(summary.total_time, 0.0);
+        assert_eq!(summary.peak_memory, None);
+    }
+
+    #[test]
+    fn non_zero_status_without_a_verdict_is_a_runtime_error() {
+        let results = vec![
+            phase_result("a", 0, PhaseVerdict::Ok),
+            phase_result("b", 1, PhaseVerdict::Ok),
+            phase_result("c", 2, PhaseVerdict::RuntimeError),
+        ];
+
+        let summary = RunSummaryDTO::new(&results, 3);
+
+        assert_eq!(summary.verdict, PhaseVerdict::RuntimeError);
+        assert_eq!(summary.failed_phase_index, Some(1));
+        assert_eq!(summary.failed_phase_name.as_deref(), Some("b"));
+    }
 }
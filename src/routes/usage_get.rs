@@ -0,0 +1,67 @@
+use crate::auth::Client;
+use crate::usage;
+use actix_web::{get, web::Json};
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub struct RateLimitDTO {
+    requests_per_second: f64,
+    burst: f64,
+    /// Requests that can be sent right away
+    remaining: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UsageLimitDTO<T> {
+    used: T,
+    /// `null` when unlimited
+    max: Option<T>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UsageResponseDTO {
+    /// API key name, or IP address when authentication is disabled
+    client: String,
+    rate_limit: Option<RateLimitDTO>,
+    /// Seconds covered by `runs` and `cpu_time`
+    quota_window: f64,
+    runs: UsageLimitDTO<u64>,
+    cpu_time: UsageLimitDTO<f64>,
+    concurrent_runs: UsageLimitDTO<usize>,
+}
+
+/// Current usage of the caller, along with the limits of its policy.
+#[get("/usage")]
+pub async fn route(client: Client) -> Json<UsageResponseDTO> {
+    let snapshot = usage::snapshot(&client);
+    let policy = &client.policy;
+
+    Json(UsageResponseDTO {
+        client: client.id(),
+        rate_limit: match (
+            policy.requests_per_second(),
+            policy.burst(),
+            snapshot.remaining_requests,
+        ) {
+            (Some(requests_per_second), Some(burst), Some(remaining)) => Some(RateLimitDTO {
+                requests_per_second,
+                burst,
+                remaining,
+            }),
+            _ => None,
+        },
+        quota_window: policy.quota_window().as_secs_f64(),
+        runs: UsageLimitDTO {
+            used: snapshot.runs as u64,
+            max: policy.max_runs_per_window(),
+        },
+        cpu_time: UsageLimitDTO {
+            used: snapshot.cpu_time,
+            max: policy.max_cpu_time_per_window(),
+        },
+        concurrent_runs: UsageLimitDTO {
+            used: snapshot.running_runs,
+            max: policy.max_concurrent_runs(),
+        },
+    })
+}
@@ -1,2 +1,5 @@
+pub mod mounts;
 pub mod phase_settings;
+#[allow(clippy::module_inception)]
 pub mod runner;
+pub mod verdict;
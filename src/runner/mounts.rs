@@ -0,0 +1,35 @@
+use crate::isolate::IsolatedBoxMount;
+use std::collections::HashMap;
+use std::{env, io};
+
+fn parse_mounts() -> Result<HashMap<String, IsolatedBoxMount>, String> {
+    match env::var("MOUNTS") {
+        Ok(value) => serde_json::from_str(&value)
+            .map_err(|e| format!("Failed to parse environment variable 'MOUNTS': {}", e)),
+        Err(_) => Ok(HashMap::new()),
+    }
+}
+
+lazy_static! {
+    /// Parsed once, a malformed `MOUNTS` refuses to start rather than silently disabling every mount
+    static ref MOUNTS: Result<HashMap<String, IsolatedBoxMount>, String> = parse_mounts();
+    static ref NO_MOUNTS: HashMap<String, IsolatedBoxMount> = HashMap::new();
+}
+
+/// Checks `MOUNTS` at startup, godbox refuses to start when it is malformed.
+pub fn init() -> io::Result<()> {
+    match MOUNTS.as_ref() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e.clone())),
+    }
+}
+
+/// Mount points declared by the administrator in the `MOUNTS` environment variable,
+/// a JSON object mapping a name to a mount, e.g. `{"gcc": {"box_path": "/opt/gcc", "host_path": "/opt/gcc"}}`.
+/// Requests can only select mounts by name from this list.
+pub fn declared_mounts() -> &'static HashMap<String, IsolatedBoxMount> {
+    match MOUNTS.as_ref() {
+        Ok(mounts) => mounts,
+        Err(_) => &NO_MOUNTS,
+    }
+}
@@ -1,33 +1,186 @@
-use crate::isolate::{IsolatedBoxOptions, IsolatedBoxOptionsBuilder};
+use crate::auth::PolicyLimits;
+use crate::isolate::{
+    self, IsolatedBoxInitOptions, IsolatedBoxInitOptionsBuilder, IsolatedBoxOptions,
+    IsolatedBoxOptionsBuilder, IsolatedCommand,
+};
+use crate::utils::{self, units, validators};
+
+use super::mounts;
 use merge::Merge;
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use validator::Validate;
 
-#[derive(Deserialize, Debug, Clone, Default, Merge, Validate)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Merge, Validate)]
 pub struct PhaseSandboxSettings {
-    pub run_time_limit: Option<u64>,
-    pub extra_time_limit: Option<u64>,
-    pub wall_time_limit: Option<u64>,
+    /// Seconds, fractional values and strings like `"500ms"` or `"2.5s"` are accepted
+    #[serde(default, deserialize_with = "units::deserialize_seconds")]
+    pub run_time_limit: Option<f64>,
+    #[serde(default, deserialize_with = "units::deserialize_seconds")]
+    pub extra_time_limit: Option<f64>,
+    #[serde(default, deserialize_with = "units::deserialize_seconds")]
+    pub wall_time_limit: Option<f64>,
+
+    /// Kilobytes, strings like `"256MB"` or `"1GiB"` are accepted
+    #[serde(default, deserialize_with = "units::deserialize_kilobytes")]
     pub stack_size_limit: Option<u64>,
     pub process_count_limit: Option<u64>,
+    #[serde(default, deserialize_with = "units::deserialize_kilobytes")]
     pub memory_limit: Option<u64>,
+    #[serde(default, deserialize_with = "units::deserialize_kilobytes")]
     pub storage_limit: Option<u64>,
+
+    pub open_files_limit: Option<u64>,
+    #[serde(default, deserialize_with = "units::deserialize_kilobytes")]
+    pub core_size_limit: Option<u64>,
+    #[serde(default, deserialize_with = "units::deserialize_kilobytes")]
+    pub address_space_limit: Option<u64>,
+
+    /// Only applied when the box is initialized, thus only allowed in the global settings
+    pub disk_quota_blocks: Option<u64>,
+    pub disk_quota_inodes: Option<u64>,
+
+    pub cg_timing: Option<bool>,
+
+    /// Names of the mounts declared by the administrator
+    pub mounts: Option<Vec<String>>,
+
+    /// Share the host network, requires `ALLOW_NETWORK`
+    pub network: Option<bool>,
+}
+
+impl PhaseSandboxSettings {
+    /// Disk quota blocks and inodes, falling back to the admin defaults.
+    pub fn disk_quota(&self) -> (Option<u64>, Option<u64>) {
+        (
+            self.disk_quota_blocks
+                .or_else(|| utils::parsed_env::get_optional("MAX_DISK_QUOTA_BLOCKS")),
+            self.disk_quota_inodes
+                .or_else(|| utils::parsed_env::get_optional("MAX_DISK_QUOTA_INODES")),
+        )
+    }
+
+    /// Sets the unset limits to their default, lowered to the policy's maximum. Without this, a
+    /// client could get more than its policy allows by leaving a limit out.
+    pub fn cap_defaults(&mut self, limits: &PolicyLimits) {
+        let defaults = IsolatedBoxOptionsBuilder::default().build().unwrap();
+        let (disk_quota_blocks, disk_quota_inodes) = PhaseSandboxSettings::default().disk_quota();
+
+        macro_rules! cap_default {
+            ($field:ident) => {
+                if let (None, Some(max)) = (self.$field, limits.$field) {
+                    self.$field = Some(defaults.$field.min(max));
+                }
+            };
+            // Unlimited when the default is `None`
+            ($field:ident, $default:expr) => {
+                if let (None, Some(max)) = (self.$field, limits.$field) {
+                    self.$field = Some($default.map_or(max, |default| default.min(max)));
+                }
+            };
+        }
+
+        cap_default!(run_time_limit);
+        cap_default!(extra_time_limit);
+        cap_default!(wall_time_limit);
+        cap_default!(stack_size_limit);
+        cap_default!(process_count_limit);
+        cap_default!(memory_limit);
+        cap_default!(storage_limit);
+        cap_default!(open_files_limit);
+        cap_default!(core_size_limit);
+        cap_default!(address_space_limit, defaults.address_space_limit);
+        cap_default!(disk_quota_blocks, disk_quota_blocks);
+        cap_default!(disk_quota_inodes, disk_quota_inodes);
+    }
+}
+
+/// Reports a default disk quota set in only one of `MAX_DISK_QUOTA_BLOCKS` and `MAX_DISK_QUOTA_INODES`,
+/// it is never applied.
+pub fn check_environment() {
+    if let (Some(_), None) | (None, Some(_)) = PhaseSandboxSettings::default().disk_quota() {
+        tracing::warn!(
+            "MAX_DISK_QUOTA_BLOCKS and MAX_DISK_QUOTA_INODES must be set together, the default disk quota is disabled"
+        );
+    }
+}
+
+/// Environment variables given in a request, a `null` value unsets an inherited variable
+pub type PhaseEnvironment = HashMap<String, Option<String>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PhaseInterpreter {
+    #[default]
+    Bash,
+    Sh,
+    Python3,
+}
+
+impl PhaseInterpreter {
+    pub fn path(&self) -> &'static str {
+        match self {
+            PhaseInterpreter::Bash => "/bin/bash",
+            PhaseInterpreter::Sh => "/bin/sh",
+            PhaseInterpreter::Python3 => "/usr/bin/python3",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PhaseInterpreter::Bash => "bash",
+            PhaseInterpreter::Sh => "sh",
+            PhaseInterpreter::Python3 => "python3",
+        }
+    }
 }
 
-#[derive(Deserialize, Debug, Clone, Validate)]
+#[derive(Serialize, Deserialize, Debug, Clone, Validate)]
 pub struct PhaseSettings {
+    #[validate(custom = "validators::validate_name")]
     pub name: Option<String>,
 
-    pub script: String,
+    /// Either `script` or `argv` must be given
+    #[validate(custom = "validators::validate_script_size")]
+    pub script: Option<String>,
+    pub interpreter: Option<PhaseInterpreter>,
+    #[validate(custom = "validators::validate_argv_size")]
+    pub argv: Option<Vec<String>>,
+
+    /// Working directory, relative paths are resolved from `/box`
+    #[validate(custom = "validators::validate_cwd")]
+    pub cwd: Option<String>,
+
+    #[validate(custom = "validators::validate_stdin_size")]
     pub stdin: Option<String>,
 
-    pub environment: Option<HashMap<String, String>>,
+    /// Checked by `check_body`, which reports every invalid variable
+    pub environment: Option<PhaseEnvironment>,
 
     pub sandbox_settings: Option<PhaseSandboxSettings>,
     pub profiling: Option<bool>,
 }
 
+impl PhaseSettings {
+    /// Interpreter name, or `argv` for commands executed directly
+    pub fn language(&self) -> &'static str {
+        match &self.argv {
+            Some(_) => "argv",
+            None => self.interpreter.unwrap_or_default().name(),
+        }
+    }
+
+    pub fn command(&self) -> IsolatedCommand {
+        match &self.argv {
+            Some(argv) => IsolatedCommand::Argv(argv.clone()),
+            None => IsolatedCommand::Script {
+                interpreter: self.interpreter.unwrap_or_default().path().into(),
+                content: self.script.clone().unwrap_or_default(),
+            },
+        }
+    }
+}
+
 impl From<PhaseSettings> for IsolatedBoxOptions {
     fn from(settings: PhaseSettings) -> Self {
         let mut options = IsolatedBoxOptionsBuilder::default();
@@ -60,9 +213,57 @@ impl From<PhaseSettings> for IsolatedBoxOptions {
             if let Some(storage_limit) = sandbox_settings.storage_limit {
                 options.storage_limit(storage_limit);
             }
+
+            if let Some(open_files_limit) = sandbox_settings.open_files_limit {
+                options.open_files_limit(open_files_limit);
+            }
+
+            if let Some(core_size_limit) = sandbox_settings.core_size_limit {
+                options.core_size_limit(core_size_limit);
+            }
+
+            if let Some(address_space_limit) = sandbox_settings.address_space_limit {
+                options.address_space_limit(address_space_limit);
+            }
+
+            if let Some(cg_timing) = sandbox_settings.cg_timing {
+                options.cg_timing(cg_timing);
+            }
+
+            if let Some(network) = sandbox_settings.network {
+                options.network(network);
+            }
+
+            if let Some(mount_names) = sandbox_settings.mounts {
+                let declared_mounts = mounts::declared_mounts();
+
+                options.mounts(
+                    mount_names
+                        .iter()
+                        .filter_map(|name| declared_mounts.get(name).cloned())
+                        .collect::<Vec<_>>(),
+                );
+            }
         }
 
-        options.environment(settings.environment.clone());
+        // Base variables first, then the request's (already merged) variables
+        let mut environment = isolate::base_environment();
+
+        for (key, value) in settings.environment.unwrap_or_default() {
+            match value {
+                Some(value) => environment.insert(key, value),
+                None => environment.remove(&key),
+            };
+        }
+
+        options.environment(environment);
+
+        if let Some(cwd) = settings.cwd {
+            options.cwd(match cwd.starts_with('/') {
+                true => cwd,
+                false => format!("/box/{}", cwd),
+            });
+        }
 
         if let Some(stdin) = settings.stdin {
             options.stdin(stdin);
@@ -75,3 +276,116 @@ impl From<PhaseSettings> for IsolatedBoxOptions {
         options.build().unwrap()
     }
 }
+
+impl From<&PhaseSandboxSettings> for IsolatedBoxInitOptions {
+    fn from(settings: &PhaseSandboxSettings) -> Self {
+        let mut options = IsolatedBoxInitOptionsBuilder::default();
+
+        if let (Some(blocks), Some(inodes)) = settings.disk_quota() {
+            options.disk_quota((blocks, inodes));
+        }
+
+        options.build().unwrap()
+    }
+}
+
+/// Sandbox settings actually applied to a phase, once defaults and global settings are merged
+#[derive(Serialize, Debug, Clone)]
+pub struct EffectiveSandboxSettings {
+    pub run_time_limit: f64,
+    pub extra_time_limit: f64,
+    pub wall_time_limit: f64,
+    pub stack_size_limit: u64,
+    pub process_count_limit: u64,
+    pub memory_limit: u64,
+    pub storage_limit: u64,
+    pub open_files_limit: u64,
+    pub core_size_limit: u64,
+    pub address_space_limit: Option<u64>,
+    pub cg_timing: bool,
+    pub network: bool,
+    pub mounts: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct EffectivePhaseSettings {
+    pub environment: BTreeMap<String, String>,
+    pub cwd: String,
+    pub profiling: bool,
+    pub sandbox_settings: EffectiveSandboxSettings,
+}
+
+impl EffectivePhaseSettings {
+    pub fn new(settings: &PhaseSettings, options: &IsolatedBoxOptions) -> EffectivePhaseSettings {
+        let declared_mounts = mounts::declared_mounts();
+
+        let mount_names = settings
+            .sandbox_settings
+            .as_ref()
+            .and_then(|sandbox_settings| sandbox_settings.mounts.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|name| declared_mounts.contains_key(name))
+            .collect();
+
+        EffectivePhaseSettings {
+            environment: options.environment.clone().into_iter().collect(),
+            cwd: options.cwd.clone().unwrap_or_else(|| "/box".into()),
+            profiling: options.profiling,
+            sandbox_settings: EffectiveSandboxSettings {
+                run_time_limit: options.run_time_limit,
+                extra_time_limit: options.extra_time_limit,
+                wall_time_limit: options.wall_time_limit,
+                stack_size_limit: options.stack_size_limit,
+                process_count_limit: options.process_count_limit,
+                memory_limit: options.memory_limit,
+                storage_limit: options.storage_limit,
+                open_files_limit: options.open_files_limit,
+                core_size_limit: options.core_size_limit,
+                address_space_limit: options.address_space_limit,
+                cg_timing: options.cg_timing,
+                network: options.network,
+                mounts: mount_names,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_limits_lower_the_defaults() {
+        // Defaults of `MAX_RUN_TIME_LIMIT`, `MAX_MEMORY_LIMIT` and `MAX_PROCESS_COUNT_LIMIT`
+        let defaults = IsolatedBoxOptionsBuilder::default().build().unwrap();
+        assert_eq!(defaults.run_time_limit, 5.0);
+        assert_eq!(defaults.memory_limit, 512000);
+        assert_eq!(defaults.process_count_limit, 120);
+
+        let mut settings = PhaseSandboxSettings {
+            process_count_limit: Some(10),
+            ..Default::default()
+        };
+
+        settings.cap_defaults(&PolicyLimits {
+            // Below the defaults
+            run_time_limit: Some(2.0),
+            process_count_limit: Some(50),
+            // Above the default
+            memory_limit: Some(1024000),
+            // Unlimited by default
+            address_space_limit: Some(65536),
+            ..Default::default()
+        });
+
+        assert_eq!(settings.run_time_limit, Some(2.0));
+        assert_eq!(settings.memory_limit, Some(512000));
+        assert_eq!(settings.address_space_limit, Some(65536));
+        // Values given in the request are left to the policy checks
+        assert_eq!(settings.process_count_limit, Some(10));
+        // Without a policy limit, the default applies later on
+        assert_eq!(settings.wall_time_limit, None);
+        assert_eq!(settings.stack_size_limit, None);
+    }
+}
@@ -1,14 +1,20 @@
-use crate::api_helpers::ApiError;
+use crate::api_helpers::{ApiError, ApiErrorCode};
 use crate::isolate::{
-    Isolate, IsolateMetadataBuilder, IsolatedBox, IsolatedBoxOptions, IsolatedBoxOptionsBuilder,
-    IsolatedExecutedCommandResult,
+    Isolate, IsolatedBox, IsolatedBoxInitOptions, IsolatedBoxOptions, IsolatedBoxOptionsBuilder,
+    IsolatedCommand, IsolatedExecutedCommandResult,
 };
+use crate::jobs::Job;
+use crate::metrics;
+use chrono::{DateTime, Utc};
 use serde::Serialize;
 use std::io;
-use std::os::unix::prelude::ExitStatusExt;
+use std::os::unix::process::ExitStatusExt;
 use std::process::ExitStatus;
+use std::sync::Arc;
+use std::time::Instant;
 
-use super::phase_settings::PhaseSettings;
+use super::phase_settings::{EffectivePhaseSettings, PhaseSettings};
+use super::verdict::{signal_name, PhaseVerdict};
 
 #[derive(Serialize, Debug, Clone)]
 pub struct RunnerPhaseResult {
@@ -19,20 +25,36 @@ pub struct RunnerPhaseResult {
 
     pub time: Option<f64>,
     pub time_wall: Option<f64>,
+    /// Same as `cg_peak_memory`, kept for backward compatibility
     pub used_memory: Option<u64>,
+    pub peak_rss: Option<u64>,
+    pub cg_peak_memory: Option<u64>,
+    pub oom_killed: bool,
     pub sandbox_status: Option<String>,
+    pub verdict: PhaseVerdict,
+    pub signal: Option<i32>,
+    pub signal_name: Option<String>,
     pub csw_voluntary: Option<u64>,
     pub csw_forced: Option<u64>,
+
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// Seconds, including the sandbox overhead
+    pub duration: f64,
+
+    pub settings: EffectivePhaseSettings,
 }
 
 pub struct Runner {
     isolate: Isolate,
+    job: Arc<Job>,
 }
 
 impl Runner {
-    pub fn new() -> io::Result<Runner> {
+    pub fn new(job: Arc<Job>) -> io::Result<Runner> {
         let runner = Runner {
             isolate: Isolate::new(),
+            job,
         };
 
         Ok(runner)
@@ -41,102 +63,193 @@ impl Runner {
     fn get_isolated_box(&self, isolated_box_id: u32) -> Result<&IsolatedBox, ApiError> {
         match self.isolate.boxes.get(&isolated_box_id) {
             Some(v) => Ok(v),
-            None => {
-                return ApiError::internal_server_error(format!(
-                    "Unknown isolated box ID: {}",
-                    isolated_box_id
-                ))
-                .into()
-            }
+            None => ApiError::internal_server_error(
+                ApiErrorCode::InternalError,
+                format!("Unknown isolated box ID: {}", isolated_box_id),
+            )
+            .into(),
         }
     }
 
     fn cleanup_isolated_box(&mut self, isolated_box_id: u32) -> Result<(), ApiError> {
-        if let Err(e) = self.isolate.destroy_box(isolated_box_id) {
-            println!("Failed to cleanup the box {}: {}", isolated_box_id, e);
+        let _span = tracing::info_span!("box_cleanup", box_id = isolated_box_id).entered();
+        let start = Instant::now();
+
+        let result = self.isolate.destroy_box(isolated_box_id);
+
+        // The box is no longer tracked either way, failures are counted apart
+        metrics::ACTIVE_BOXES.dec();
+
+        match result {
+            Ok(_) => {
+                tracing::info!(box_id = isolated_box_id, "Box cleaned up");
+            }
+            Err(e) => {
+                metrics::BOX_CLEANUP_FAILURES.inc();
+                tracing::error!(box_id = isolated_box_id, "Failed to cleanup the box: {}", e);
+            }
         }
 
+        metrics::BOX_CLEANUP_DURATION.observe(start.elapsed().as_secs_f64());
+
         Ok(())
     }
 
-    pub fn setup(&mut self, files: &String) -> Result<u32, ApiError> {
-        let isolated_box = match self.isolate.init_box() {
-            Ok(v) => v,
+    /// Cleans up the box once every phase ran, unless it was already cleaned up after an error.
+    pub fn teardown(&mut self, isolated_box_id: u32) -> Result<(), ApiError> {
+        match self.isolate.boxes.contains_key(&isolated_box_id) {
+            true => self.cleanup_isolated_box(isolated_box_id),
+            false => Ok(()),
+        }
+    }
+
+    pub fn setup(&mut self, files: &str, options: IsolatedBoxInitOptions) -> Result<u32, ApiError> {
+        let start = Instant::now();
+        let isolated_box =
+            tracing::info_span!("box_init").in_scope(|| self.isolate.init_box(options));
+
+        metrics::BOX_INIT_DURATION.observe(start.elapsed().as_secs_f64());
+
+        let isolated_box = match isolated_box {
+            Ok(v) => {
+                metrics::ACTIVE_BOXES.inc();
+                tracing::info!(box_id = v.box_id, "Box initialized");
+                v
+            }
             Err(e) => {
-                return ApiError::internal_server_error(format!(
-                    "Failed to initialize a new box: {}",
-                    e
-                ))
-                .into()
+                metrics::BOX_INIT_FAILURES.inc();
+                tracing::error!("Failed to initialize a new box: {}", e);
+
+                return ApiError::internal_server_error(
+                    ApiErrorCode::BoxInitFailed,
+                    format!("Failed to initialize a new box: {}", e),
+                )
+                .into();
             }
         };
 
-        let files_buffer = match base64::decode(&files) {
+        let files_buffer = match base64::decode(files) {
             Ok(buf) => buf,
             Err(e) => {
-                return ApiError::bad_request(format!("Error while reading files: {}", e)).into()
+                self.cleanup_isolated_box(isolated_box.box_id)?;
+
+                return ApiError::bad_request(
+                    ApiErrorCode::InvalidFilesEncoding,
+                    format!("Error while reading files: {}", e),
+                )
+                .with_field("files")
+                .into();
             }
         };
 
-        if let Err(e) = isolated_box.upload_file("/box/files.zip", &files_buffer) {
-            return ApiError::internal_server_error(format!(
-                "Failed to upload files into the isolated environment: {}",
-                e,
-            ))
+        let upload_result = tracing::info_span!("file_upload", box_id = isolated_box.box_id)
+            .in_scope(|| isolated_box.upload_file("/box/files.zip", &files_buffer));
+
+        if let Err(e) = upload_result {
+            self.cleanup_isolated_box(isolated_box.box_id)?;
+
+            return ApiError::internal_server_error(
+                ApiErrorCode::FileUploadFailed,
+                format!(
+                    "Failed to upload files into the isolated environment: {}",
+                    e
+                ),
+            )
             .into();
         }
 
-        let unzip_result = self.exec_isolated_box(
-            &isolated_box,
-            "/usr/bin/unzip -n -qq /box/files.zip && /bin/rm /box/files.zip",
-            IsolatedBoxOptionsBuilder::default().build().unwrap(),
-        );
+        let unzip_result = tracing::info_span!("file_extraction", box_id = isolated_box.box_id)
+            .in_scope(|| {
+                self.exec_isolated_box(
+                    &isolated_box,
+                    IsolatedCommand::Script {
+                        interpreter: "/bin/bash".into(),
+                        content: "/usr/bin/unzip -n -qq /box/files.zip && /bin/rm /box/files.zip"
+                            .into(),
+                    },
+                    IsolatedBoxOptionsBuilder::default().build().unwrap(),
+                )
+            });
+
+        let unzip_result = match unzip_result {
+            Ok(v) => v,
+            Err(e) => {
+                self.cleanup_isolated_box(isolated_box.box_id)?;
+
+                return Err(e);
+            }
+        };
+
+        if PhaseVerdict::from(&unzip_result.metadata) == PhaseVerdict::SandboxError {
+            self.cleanup_isolated_box(isolated_box.box_id)?;
+
+            return ApiError::internal_server_error(
+                ApiErrorCode::SandboxError,
+                "Failed to extract files in the isolated environment",
+            )
+            .into();
+        }
 
         if !unzip_result.status.success() {
             self.cleanup_isolated_box(isolated_box.box_id)?;
 
-            return ApiError::bad_request(format!(
-                "Error while unzipping files: {}",
-                unzip_result.stderr
-            ))
+            return ApiError::bad_request(
+                ApiErrorCode::InvalidArchive,
+                format!("Error while unzipping files: {}", unzip_result.stderr),
+            )
+            .with_field("files")
             .into();
         }
 
         Ok(isolated_box.box_id)
     }
 
-    fn exec_isolated_box<S>(
+    fn exec_isolated_box(
         &self,
         isolated_box: &IsolatedBox,
-        script: S,
+        command: IsolatedCommand,
         options: IsolatedBoxOptions,
-    ) -> IsolatedExecutedCommandResult
-    where
-        S: Into<String>,
-    {
-        match isolated_box.exec(script, options) {
-            Ok(result) => result,
-            Err(e) => IsolatedExecutedCommandResult {
-                status: ExitStatus::from_raw(1),
-                stderr: e.to_string(),
-                stdout: "".to_string(),
-                metadata: IsolateMetadataBuilder::default().build().unwrap(),
-            },
+    ) -> Result<IsolatedExecutedCommandResult, ApiError> {
+        match isolated_box.exec(command, options, Some(&self.job)) {
+            Ok(result) => {
+                if PhaseVerdict::from(&result.metadata) == PhaseVerdict::SandboxError {
+                    tracing::error!(
+                        box_id = isolated_box.box_id,
+                        "Internal error of the isolated box: {}",
+                        result.metadata.message.as_deref().unwrap_or(&result.stderr)
+                    );
+                }
+
+                Ok(result)
+            }
+            Err(e) => {
+                tracing::error!(
+                    box_id = isolated_box.box_id,
+                    "Failed to execute a command in the isolated box: {}",
+                    e
+                );
+
+                ApiError::internal_server_error(
+                    ApiErrorCode::SandboxError,
+                    format!(
+                        "Failed to execute the command in the isolated environment: {}",
+                        e
+                    ),
+                )
+                .into()
+            }
         }
     }
 
-    fn exec<S>(
+    fn exec(
         &self,
         isolated_box_id: u32,
-        script: S,
+        command: IsolatedCommand,
         options: IsolatedBoxOptions,
-    ) -> Result<IsolatedExecutedCommandResult, ApiError>
-    where
-        S: Into<String>,
-    {
+    ) -> Result<IsolatedExecutedCommandResult, ApiError> {
         let isolated_box = self.get_isolated_box(isolated_box_id)?;
 
-        Ok(self.exec_isolated_box(&isolated_box, script, options))
+        self.exec_isolated_box(isolated_box, command, options)
     }
 
     pub fn run_phase(
@@ -144,10 +257,75 @@ impl Runner {
         isolated_box_id: u32,
         settings: &PhaseSettings,
     ) -> Result<RunnerPhaseResult, ApiError> {
-        let result = self.exec(isolated_box_id, &settings.script, settings.clone().into())?;
+        let _span = tracing::info_span!(
+            "phase",
+            box_id = isolated_box_id,
+            phase = settings.name.as_deref().unwrap_or_default()
+        )
+        .entered();
+
+        let options: IsolatedBoxOptions = settings.clone().into();
+        let effective_settings = EffectivePhaseSettings::new(settings, &options);
+
+        let started_at = Utc::now();
+        let start = Instant::now();
+
+        let result = match self.exec(isolated_box_id, settings.command(), options) {
+            Ok(v) => v,
+            // isolate was interrupted before it could report anything
+            Err(_) if self.job.was_interrupted() => IsolatedExecutedCommandResult {
+                status: ExitStatus::from_raw(libc::SIGTERM),
+                stdout: String::new(),
+                stderr: String::new(),
+                metadata: Default::default(),
+            },
+            Err(e) => {
+                self.cleanup_isolated_box(isolated_box_id)?;
+
+                return Err(e);
+            }
+        };
+
+        let duration = start.elapsed().as_secs_f64();
+        let finished_at = Utc::now();
+
+        // Only when isolate was interrupted, a phase that completed keeps its verdict
+        let verdict = match self.job.was_interrupted() {
+            true => PhaseVerdict::Cancelled,
+            false => PhaseVerdict::from(&result.metadata),
+        };
+        let language = settings.language();
+
+        tracing::info!(
+            verdict = verdict.as_str(),
+            language,
+            status = result.status.code(),
+            time = result.metadata.time,
+            time_wall = result.metadata.time_wall,
+            "Phase completed"
+        );
+
+        metrics::PHASES
+            .with_label_values(&[verdict.as_str(), language])
+            .inc();
+
+        if let Some(time) = result.metadata.time {
+            metrics::PHASE_TIME
+                .with_label_values(&[language])
+                .observe(time);
+        }
 
-        if !result.status.success() {
-            self.cleanup_isolated_box(isolated_box_id)?;
+        if let Some(time_wall) = result.metadata.time_wall {
+            metrics::PHASE_WALL_TIME
+                .with_label_values(&[language])
+                .observe(time_wall);
+        }
+
+        // Only the control group's peak, the RSS of a single process is not comparable
+        if let Some(memory) = result.metadata.cg_mem {
+            metrics::PHASE_MEMORY
+                .with_label_values(&[language])
+                .observe(memory as f64);
         }
 
         Ok(RunnerPhaseResult {
@@ -159,9 +337,36 @@ impl Runner {
             time: result.metadata.time,
             time_wall: result.metadata.time_wall,
             used_memory: result.metadata.cg_mem,
+            peak_rss: result.metadata.max_rss,
+            cg_peak_memory: result.metadata.cg_mem,
+            oom_killed: result.metadata.cg_oom_killed,
             sandbox_status: result.metadata.status,
+            verdict,
+            signal: result.metadata.exit_signal,
+            signal_name: result
+                .metadata
+                .exit_signal
+                .and_then(signal_name)
+                .map(Into::into),
             csw_voluntary: result.metadata.csw_voluntary,
             csw_forced: result.metadata.csw_forced,
+
+            started_at,
+            finished_at,
+            duration,
+
+            settings: effective_settings,
         })
     }
 }
+
+impl Drop for Runner {
+    fn drop(&mut self) {
+        // Boxes left behind when a run is interrupted by a panic
+        let box_ids = self.isolate.boxes.keys().copied().collect::<Vec<_>>();
+
+        for box_id in box_ids {
+            let _ = self.cleanup_isolated_box(box_id);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::isolate::IsolateMetadata;
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PhaseVerdict {
+    #[default]
+    Ok,
+    TimeLimit,
+    WallTimeLimit,
+    MemoryLimit,
+    RuntimeError,
+    Signaled,
+    OutputLimit,
+    SandboxError,
+    /// The job was cancelled while the phase was running
+    Cancelled,
+}
+
+impl PhaseVerdict {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PhaseVerdict::Ok => "ok",
+            PhaseVerdict::TimeLimit => "time_limit",
+            PhaseVerdict::WallTimeLimit => "wall_time_limit",
+            PhaseVerdict::MemoryLimit => "memory_limit",
+            PhaseVerdict::RuntimeError => "runtime_error",
+            PhaseVerdict::Signaled => "signaled",
+            PhaseVerdict::OutputLimit => "output_limit",
+            PhaseVerdict::SandboxError => "sandbox_error",
+            PhaseVerdict::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl From<&IsolateMetadata> for PhaseVerdict {
+    fn from(metadata: &IsolateMetadata) -> Self {
+        if metadata.cg_oom_killed {
+            return PhaseVerdict::MemoryLimit;
+        }
+
+        match metadata.status.as_deref() {
+            Some("XX") => PhaseVerdict::SandboxError,
+            Some("TO") => match &metadata.message {
+                // isolate reports "Time limit exceeded (wall clock)"
+                Some(message) if message.contains("wall clock") => PhaseVerdict::WallTimeLimit,
+                _ => PhaseVerdict::TimeLimit,
+            },
+            Some("SG") => match metadata.exit_signal {
+                // Sent by the kernel when a file grows over the `-f` (storage) limit
+                Some(libc::SIGXFSZ) => PhaseVerdict::OutputLimit,
+                _ => PhaseVerdict::Signaled,
+            },
+            Some("RE") => PhaseVerdict::RuntimeError,
+            _ => match metadata.exit_code {
+                Some(0) | None => PhaseVerdict::Ok,
+                Some(_) => PhaseVerdict::RuntimeError,
+            },
+        }
+    }
+}
+
+/// Name of a Linux signal, e.g. `SIGSEGV` for 11.
+pub fn signal_name(signal: i32) -> Option<&'static str> {
+    let name = match signal {
+        1 => "SIGHUP",
+        2 => "SIGINT",
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        5 => "SIGTRAP",
+        6 => "SIGABRT",
+        7 => "SIGBUS",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        10 => "SIGUSR1",
+        11 => "SIGSEGV",
+        12 => "SIGUSR2",
+        13 => "SIGPIPE",
+        14 => "SIGALRM",
+        15 => "SIGTERM",
+        16 => "SIGSTKFLT",
+        17 => "SIGCHLD",
+        18 => "SIGCONT",
+        19 => "SIGSTOP",
+        20 => "SIGTSTP",
+        21 => "SIGTTIN",
+        22 => "SIGTTOU",
+        23 => "SIGURG",
+        24 => "SIGXCPU",
+        25 => "SIGXFSZ",
+        26 => "SIGVTALRM",
+        27 => "SIGPROF",
+        28 => "SIGWINCH",
+        29 => "SIGIO",
+        30 => "SIGPWR",
+        31 => "SIGSYS",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verdict(metadata: &str) -> PhaseVerdict {
+        PhaseVerdict::from(&metadata.parse::<IsolateMetadata>().unwrap())
+    }
+
+    #[test]
+    fn oom_kill_takes_precedence() {
+        assert_eq!(
+            verdict("cg-oom-killed:1\nstatus:SG\nexitsig:9\n"),
+            PhaseVerdict::MemoryLimit
+        );
+        assert_eq!(
+            verdict("cg-oom-killed:1\nstatus:TO\nmessage:Time limit exceeded\n"),
+            PhaseVerdict::MemoryLimit
+        );
+    }
+
+    #[test]
+    fn time_limits() {
+        assert_eq!(
+            verdict("status:TO\nmessage:Time limit exceeded (wall clock)\n"),
+            PhaseVerdict::WallTimeLimit
+        );
+        assert_eq!(
+            verdict("status:TO\nmessage:Time limit exceeded\n"),
+            PhaseVerdict::TimeLimit
+        );
+        assert_eq!(verdict("status:TO\n"), PhaseVerdict::TimeLimit);
+    }
+
+    #[test]
+    fn signals() {
+        assert_eq!(
+            verdict("status:SG\nexitsig:25\nmessage:Caught fatal signal 25\n"),
+            PhaseVerdict::OutputLimit
+        );
+        assert_eq!(
+            verdict("status:SG\nexitsig:11\nmessage:Caught fatal signal 11\n"),
+            PhaseVerdict::Signaled
+        );
+        assert_eq!(verdict("status:SG\n"), PhaseVerdict::Signaled);
+    }
+
+    #[test]
+    fn errors_and_exit_codes() {
+        assert_eq!(
+            verdict("status:XX\nmessage:execve failed\n"),
+            PhaseVerdict::SandboxError
+        );
+        assert_eq!(
+            verdict("status:RE\nexitcode:1\n"),
+            PhaseVerdict::RuntimeError
+        );
+        // isolate only reports a status for failures it detected itself
+        assert_eq!(verdict("exitcode:3\n"), PhaseVerdict::RuntimeError);
+        assert_eq!(verdict("exitcode:0\n"), PhaseVerdict::Ok);
+        assert_eq!(verdict(""), PhaseVerdict::Ok);
+    }
+
+    #[test]
+    fn signal_names() {
+        assert_eq!(signal_name(0), None);
+        assert_eq!(signal_name(1), Some("SIGHUP"));
+        assert_eq!(signal_name(libc::SIGSEGV), Some("SIGSEGV"));
+        assert_eq!(signal_name(libc::SIGXFSZ), Some("SIGXFSZ"));
+        assert_eq!(signal_name(31), Some("SIGSYS"));
+        assert_eq!(signal_name(32), None);
+        assert_eq!(signal_name(-1), None);
+    }
+}
@@ -0,0 +1,463 @@
+use crate::auth::Client;
+use crate::jobs::Job;
+use crate::{metrics, utils};
+use futures_channel::oneshot;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Priority class of a run. Queued runs of a higher class always start first.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Batch,
+    #[default]
+    Normal,
+    Interactive,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Batch => "batch",
+            Priority::Normal => "normal",
+            Priority::Interactive => "interactive",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Priority, String> {
+        match value {
+            "batch" => Ok(Priority::Batch),
+            "normal" => Ok(Priority::Normal),
+            "interactive" => Ok(Priority::Interactive),
+            _ => Err(format!("unknown priority '{}'", value)),
+        }
+    }
+}
+
+struct QueuedRun {
+    ticket: u64,
+    job_id: String,
+    priority: Priority,
+    tenant: String,
+    weight: f64,
+    queued_at: Instant,
+    sender: oneshot::Sender<()>,
+}
+
+/// Runs are started in the order of their priority class, then of their tenant's start tag
+/// (start-time fair queuing), so that tenants get a share of the runs proportional to their weight.
+#[derive(Default)]
+struct Scheduler {
+    running: usize,
+    next_ticket: u64,
+    queue: Vec<QueuedRun>,
+
+    /// Virtual time of the last started run
+    virtual_time: f64,
+    /// Finish tag of the last run started by each tenant
+    finish_tags: HashMap<String, f64>,
+}
+
+/// Runs executed at the same time across every client, defaults to the number of CPUs
+fn max_running_runs() -> usize {
+    utils::parsed_env::get("MAX_RUNNING_RUNS", num_cpus::get()).max(1)
+}
+
+impl Scheduler {
+    fn start_tag(&self, tenant: &str) -> f64 {
+        self.finish_tags
+            .get(tenant)
+            .map_or(self.virtual_time, |tag| tag.max(self.virtual_time))
+    }
+
+    fn charge(&mut self, tenant: &str, weight: f64) {
+        let start_tag = self.start_tag(tenant);
+
+        self.virtual_time = start_tag;
+        self.finish_tags
+            .insert(tenant.into(), start_tag + 1.0 / weight);
+        self.running += 1;
+
+        // Tenants behind the virtual time have no advantage left to remember
+        let virtual_time = self.virtual_time;
+        self.finish_tags.retain(|_, tag| *tag > virtual_time);
+    }
+
+    fn next(&self) -> Option<usize> {
+        self.queue
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                b.priority
+                    .cmp(&a.priority)
+                    .then_with(|| {
+                        self.start_tag(&a.tenant)
+                            .partial_cmp(&self.start_tag(&b.tenant))
+                            .unwrap()
+                    })
+                    .then_with(|| a.ticket.cmp(&b.ticket))
+            })
+            .map(|(i, _)| i)
+    }
+
+    /// Starts queued runs while there are free slots.
+    fn dispatch(&mut self) {
+        let max = max_running_runs();
+
+        while self.running < max {
+            let queued_run = match self.next() {
+                Some(i) => self.queue.remove(i),
+                None => break,
+            };
+
+            let priority = queued_run.priority.as_str();
+
+            metrics::QUEUED_RUNS.with_label_values(&[priority]).dec();
+            metrics::QUEUE_WAIT_DURATION
+                .with_label_values(&[priority])
+                .observe(queued_run.queued_at.elapsed().as_secs_f64());
+
+            self.charge(&queued_run.tenant, queued_run.weight);
+
+            // When the waiting request is gone, its ticket releases the slot
+            let _ = queued_run.sender.send(());
+        }
+    }
+
+    fn release(&mut self) {
+        self.running = self.running.saturating_sub(1);
+        self.dispatch();
+    }
+
+    fn enqueue(
+        &mut self,
+        job_id: String,
+        priority: Priority,
+        tenant: String,
+        weight: f64,
+    ) -> (u64, oneshot::Receiver<()>) {
+        let (sender, receiver) = oneshot::channel();
+        let ticket = self.next_ticket;
+
+        self.next_ticket += 1;
+        self.queue.push(QueuedRun {
+            ticket,
+            job_id,
+            priority,
+            tenant,
+            weight,
+            queued_at: Instant::now(),
+            sender,
+        });
+
+        metrics::QUEUED_RUNS
+            .with_label_values(&[priority.as_str()])
+            .inc();
+
+        (ticket, receiver)
+    }
+
+    /// Removes a run from the queue, or releases its slot if it was already started.
+    fn leave(&mut self, ticket: u64) {
+        match self.queue.iter().position(|run| run.ticket == ticket) {
+            Some(i) => self.remove(i),
+            None => self.release(),
+        }
+    }
+
+    fn remove(&mut self, i: usize) {
+        let queued_run = self.queue.remove(i);
+
+        metrics::QUEUED_RUNS
+            .with_label_values(&[queued_run.priority.as_str()])
+            .dec();
+    }
+}
+
+lazy_static! {
+    static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::default());
+}
+
+/// Slot of a started run, the next queued run starts once it is dropped.
+pub struct RunPermit {
+    _private: (),
+}
+
+impl Drop for RunPermit {
+    fn drop(&mut self) {
+        SCHEDULER.lock().unwrap().release();
+    }
+}
+
+/// Place of a request in the queue, removed if the request is dropped while waiting.
+struct QueueTicket {
+    ticket: u64,
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        SCHEDULER.lock().unwrap().leave(self.ticket);
+    }
+}
+
+/// Removes a job of a client from the queue, its run will not be started.
+pub fn dequeue(tenant: &str, job_id: &str) {
+    let mut scheduler = SCHEDULER.lock().unwrap();
+
+    if let Some(i) = scheduler
+        .queue
+        .iter()
+        .position(|run| run.tenant == tenant && run.job_id == job_id)
+    {
+        scheduler.remove(i);
+    }
+}
+
+/// Waits for a free slot to run. Waiting runs are started by priority class, then fairly
+/// between clients according to their policy's `weight`. Gives `None` if the job is cancelled.
+pub async fn schedule(client: &Client, priority: Priority, job: &Job) -> Option<RunPermit> {
+    let weight = client.policy.weight();
+    let tenant = client.id();
+
+    let (ticket, receiver) = {
+        let mut scheduler = SCHEDULER.lock().unwrap();
+
+        // Checked under the lock: jobs cancelled from now on are dequeued after being queued
+        if job.is_cancelled() {
+            return None;
+        }
+
+        if scheduler.queue.is_empty() && scheduler.running < max_running_runs() {
+            metrics::QUEUE_WAIT_DURATION
+                .with_label_values(&[priority.as_str()])
+                .observe(0.0);
+
+            scheduler.charge(&tenant, weight);
+
+            return Some(RunPermit { _private: () });
+        }
+
+        let (ticket, receiver) = scheduler.enqueue(job.id.clone(), priority, tenant, weight);
+
+        tracing::info!(
+            priority = priority.as_str(),
+            queued = scheduler.queue.len(),
+            "Run queued"
+        );
+
+        (QueueTicket { ticket }, receiver)
+    };
+
+    // The sender is dropped without a message when the job is dequeued
+    let started = receiver.await.is_ok();
+
+    // Either the slot is now held by the permit, or the run already left the queue
+    std::mem::forget(ticket);
+
+    // The job may have been cancelled after it was dispatched, its slot is then released
+    match started {
+        true if !job.is_cancelled() => Some(RunPermit { _private: () }),
+        true => {
+            SCHEDULER.lock().unwrap().release();
+
+            None
+        }
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enqueue(
+        scheduler: &mut Scheduler,
+        tenant: &str,
+        weight: f64,
+        priority: Priority,
+    ) -> (u64, oneshot::Receiver<()>) {
+        scheduler.enqueue(String::new(), priority, tenant.into(), weight)
+    }
+
+    fn enqueue_ticket(
+        scheduler: &mut Scheduler,
+        tenant: &str,
+        weight: f64,
+        priority: Priority,
+    ) -> u64 {
+        enqueue(scheduler, tenant, weight, priority).0
+    }
+
+    /// Starts the next queued run, as `dispatch` does with a free slot
+    fn start_next(scheduler: &mut Scheduler) -> QueuedRun {
+        let i = scheduler.next().unwrap();
+        let queued_run = scheduler.queue.remove(i);
+
+        scheduler.charge(&queued_run.tenant, queued_run.weight);
+
+        queued_run
+    }
+
+    fn start_order(scheduler: &mut Scheduler) -> String {
+        let mut order = String::new();
+
+        while !scheduler.queue.is_empty() {
+            order.push_str(&start_next(scheduler).tenant);
+        }
+
+        order
+    }
+
+    #[test]
+    fn higher_priority_classes_start_first() {
+        let mut scheduler = Scheduler::default();
+
+        enqueue_ticket(&mut scheduler, "a", 100.0, Priority::Batch);
+        enqueue_ticket(&mut scheduler, "b", 1.0, Priority::Normal);
+        enqueue_ticket(&mut scheduler, "c", 1.0, Priority::Interactive);
+        enqueue_ticket(&mut scheduler, "a", 100.0, Priority::Normal);
+        enqueue_ticket(&mut scheduler, "d", 1.0, Priority::Interactive);
+
+        let priorities = std::iter::from_fn(|| match scheduler.queue.is_empty() {
+            true => None,
+            false => Some(start_next(&mut scheduler).priority),
+        })
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            priorities,
+            vec![
+                Priority::Interactive,
+                Priority::Interactive,
+                Priority::Normal,
+                Priority::Normal,
+                Priority::Batch
+            ]
+        );
+    }
+
+    #[test]
+    fn runs_of_a_class_start_in_queue_order() {
+        let mut scheduler = Scheduler::default();
+
+        let tickets = (0..4)
+            .map(|_| enqueue_ticket(&mut scheduler, "a", 1.0, Priority::Normal))
+            .collect::<Vec<_>>();
+        let started = (0..4)
+            .map(|_| start_next(&mut scheduler).ticket)
+            .collect::<Vec<_>>();
+
+        assert_eq!(started, tickets);
+    }
+
+    #[test]
+    fn tenants_share_runs_by_weight() {
+        let mut scheduler = Scheduler::default();
+
+        for _ in 0..6 {
+            enqueue_ticket(&mut scheduler, "a", 2.0, Priority::Normal);
+        }
+
+        for _ in 0..6 {
+            enqueue_ticket(&mut scheduler, "b", 1.0, Priority::Normal);
+        }
+
+        assert_eq!(start_order(&mut scheduler), "abaabaababbb");
+    }
+
+    #[test]
+    fn equal_weights_alternate() {
+        let mut scheduler = Scheduler::default();
+
+        for _ in 0..3 {
+            enqueue_ticket(&mut scheduler, "a", 1.0, Priority::Normal);
+        }
+
+        for _ in 0..3 {
+            enqueue_ticket(&mut scheduler, "b", 1.0, Priority::Normal);
+        }
+
+        assert_eq!(start_order(&mut scheduler), "ababab");
+    }
+
+    #[test]
+    fn idle_tenants_do_not_bank_their_share() {
+        let mut scheduler = Scheduler::default();
+
+        // "a" runs alone for a while, "b" arrives later and must not starve it
+        for _ in 0..4 {
+            enqueue_ticket(&mut scheduler, "a", 1.0, Priority::Normal);
+            start_next(&mut scheduler);
+        }
+
+        for _ in 0..2 {
+            enqueue_ticket(&mut scheduler, "a", 1.0, Priority::Normal);
+        }
+
+        for _ in 0..2 {
+            enqueue_ticket(&mut scheduler, "b", 1.0, Priority::Normal);
+        }
+
+        assert_eq!(start_order(&mut scheduler), "baba");
+    }
+
+    #[test]
+    fn leaving_the_queue_removes_the_run() {
+        let mut scheduler = Scheduler::default();
+
+        let first = enqueue_ticket(&mut scheduler, "a", 1.0, Priority::Normal);
+        let second = enqueue_ticket(&mut scheduler, "b", 1.0, Priority::Normal);
+
+        scheduler.leave(first);
+
+        assert_eq!(scheduler.running, 0);
+        assert_eq!(scheduler.queue.len(), 1);
+        assert_eq!(scheduler.queue[0].ticket, second);
+    }
+
+    #[test]
+    fn leaving_after_start_releases_the_slot() {
+        let mut scheduler = Scheduler::default();
+        let max = max_running_runs();
+
+        let started = enqueue_ticket(&mut scheduler, "a", 1.0, Priority::Normal);
+        start_next(&mut scheduler);
+
+        // Every slot is taken, the next run waits
+        scheduler.running = max;
+        let (_, mut receiver) = enqueue(&mut scheduler, "b", 1.0, Priority::Normal);
+
+        scheduler.dispatch();
+        assert_eq!(receiver.try_recv(), Ok(None));
+
+        scheduler.leave(started);
+
+        assert_eq!(receiver.try_recv(), Ok(Some(())));
+        assert_eq!(scheduler.running, max);
+        assert!(scheduler.queue.is_empty());
+    }
+
+    #[test]
+    fn releasing_a_slot_starts_the_next_run() {
+        let mut scheduler = Scheduler::default();
+        let max = max_running_runs();
+
+        scheduler.running = max;
+
+        let (_, mut batch) = enqueue(&mut scheduler, "a", 1.0, Priority::Batch);
+        let (_, mut interactive) = enqueue(&mut scheduler, "b", 1.0, Priority::Interactive);
+
+        scheduler.release();
+
+        assert_eq!(interactive.try_recv(), Ok(Some(())));
+        assert_eq!(batch.try_recv(), Ok(None));
+        assert_eq!(scheduler.running, max);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::utils;
+use actix_web::http::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::Context;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracerProvider, Tracer};
+use opentelemetry_sdk::Resource;
+use std::env;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Traces are only exported when an OTLP endpoint is configured, through the standard
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` environment variables.
+fn is_enabled() -> bool {
+    env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some()
+        || env::var_os("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_some()
+}
+
+/// Exports spans in batches with OTLP over HTTP. The exporter runs on its own thread, so it
+/// does not depend on the server's runtime. Errors are returned to be logged once the logger
+/// is set up, traces are then disabled.
+pub fn tracer_provider() -> Result<Option<SdkTracerProvider>, String> {
+    if !is_enabled() {
+        return Ok(None);
+    }
+
+    let exporter = SpanExporter::builder().with_http().build().map_err(|e| {
+        format!(
+            "Failed to create the OTLP exporter, traces are disabled: {}",
+            e
+        )
+    })?;
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(utils::parsed_env::get(
+                    "OTEL_SERVICE_NAME",
+                    String::from("godbox"),
+                ))
+                .build(),
+        )
+        .build();
+
+    Ok(Some(provider))
+}
+
+pub fn layer<S>(provider: &SdkTracerProvider) -> OpenTelemetryLayer<S, Tracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer("godbox"))
+}
+
+/// Flushes the spans that are not exported yet.
+pub fn shutdown(provider: SdkTracerProvider) {
+    if let Err(e) = provider.shutdown() {
+        tracing::error!("Failed to shutdown the tracer provider: {}", e);
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Trace context sent by the client in the W3C `traceparent` header, if any.
+pub fn parent_context(headers: &HeaderMap) -> Context {
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(headers))
+    })
+}
@@ -0,0 +1,501 @@
+use crate::api_helpers::{ApiError, ApiErrorCode, ApiErrorDetails};
+use crate::auth::Client;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Past this number of tracked clients, the idle ones are forgotten
+const MAX_IDLE_CLIENTS: usize = 1024;
+
+/// Usage of a client, kept in memory and reset when godbox restarts.
+struct ClientUsage {
+    running_runs: usize,
+
+    /// Tokens left in the rate limit bucket, `None` until the first request
+    tokens: Option<f64>,
+    refilled_at: Instant,
+
+    /// Start of the runs of the current window
+    runs: VecDeque<Instant>,
+    /// End and CPU time of the phases of the current window
+    cpu_time: VecDeque<(Instant, f64)>,
+
+    window: Duration,
+    last_seen: Instant,
+}
+
+impl ClientUsage {
+    fn new() -> ClientUsage {
+        ClientUsage {
+            running_runs: 0,
+            tokens: None,
+            refilled_at: Instant::now(),
+            runs: VecDeque::new(),
+            cpu_time: VecDeque::new(),
+            window: Duration::default(),
+            last_seen: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, requests_per_second: f64, burst: f64, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        let tokens = match self.tokens {
+            Some(tokens) => (tokens + elapsed * requests_per_second).min(burst),
+            None => burst,
+        };
+
+        self.tokens = Some(tokens);
+        self.refilled_at = now;
+
+        tokens
+    }
+
+    /// Forgets the usage that left the window.
+    fn expire(&mut self, now: Instant) {
+        let window = self.window;
+
+        while matches!(self.runs.front(), Some(at) if now.duration_since(*at) >= window) {
+            self.runs.pop_front();
+        }
+
+        while matches!(self.cpu_time.front(), Some((at, _)) if now.duration_since(*at) >= window) {
+            self.cpu_time.pop_front();
+        }
+    }
+
+    fn total_cpu_time(&self) -> f64 {
+        self.cpu_time
+            .iter()
+            .fold(0.0, |total, (_, time)| total + time)
+    }
+
+    /// Seconds until enough CPU time leaves the window to get under `max`
+    fn cpu_time_retry_after(&self, max: f64, now: Instant) -> Option<f64> {
+        let mut remaining = self.total_cpu_time();
+
+        self.cpu_time.iter().find_map(|(at, time)| {
+            remaining -= time;
+
+            match remaining < max {
+                true => Some(seconds_until_expiry(*at, self.window, now)),
+                false => None,
+            }
+        })
+    }
+
+    /// Nothing is running and the whole usage left the window
+    fn is_idle(&self, now: Instant) -> bool {
+        self.running_runs == 0 && now.duration_since(self.last_seen) >= self.window
+    }
+}
+
+lazy_static! {
+    static ref USAGES: Mutex<HashMap<String, ClientUsage>> = Mutex::new(HashMap::new());
+}
+
+fn with_usage<T>(client: &Client, f: impl FnOnce(&mut ClientUsage, Instant) -> T) -> T {
+    let mut usages = USAGES.lock().unwrap();
+    let now = Instant::now();
+
+    if usages.len() > MAX_IDLE_CLIENTS {
+        usages.retain(|_, usage| !usage.is_idle(now));
+    }
+
+    let usage = usages.entry(client.id()).or_insert_with(ClientUsage::new);
+    usage.window = client.policy.quota_window();
+    usage.last_seen = now;
+    usage.expire(now);
+
+    f(usage, now)
+}
+
+fn seconds_until_expiry(at: Instant, window: Duration, now: Instant) -> f64 {
+    (window.as_secs_f64() - now.duration_since(at).as_secs_f64()).max(0.0)
+}
+
+fn too_many_requests<S: Into<String>>(
+    code: ApiErrorCode,
+    message: S,
+    limit: &str,
+    max: impl Serialize,
+    value: impl Serialize,
+    retry_after: Option<f64>,
+) -> ApiError {
+    ApiError::too_many_requests(code, message).with_details(ApiErrorDetails {
+        limit: Some(limit.into()),
+        max: serde_json::to_value(max).ok(),
+        value: serde_json::to_value(value).ok(),
+        retry_after,
+        ..Default::default()
+    })
+}
+
+/// Takes a token from the client's bucket, refilled at `requests_per_second` up to `burst` tokens.
+pub fn check_rate_limit(client: &Client) -> Result<(), ApiError> {
+    let (requests_per_second, burst) =
+        match (client.policy.requests_per_second(), client.policy.burst()) {
+            (Some(requests_per_second), Some(burst)) => (requests_per_second, burst),
+            _ => return Ok(()),
+        };
+
+    with_usage(client, |usage, now| {
+        let tokens = usage.refill(requests_per_second, burst, now);
+
+        if tokens < 1.0 {
+            return Err(too_many_requests(
+                ApiErrorCode::RateLimitExceeded,
+                format!(
+                    "At most {} requests per second are allowed, with bursts of {}",
+                    requests_per_second, burst
+                ),
+                "requests_per_second",
+                requests_per_second,
+                tokens,
+                Some((1.0 - tokens) / requests_per_second),
+            ));
+        }
+
+        usage.tokens = Some(tokens - 1.0);
+
+        Ok(())
+    })
+}
+
+/// Checks the client's concurrency limit and quotas, then reserves a new run.
+/// The run is no longer in progress once the slot is dropped, and no longer counted in the quota
+/// if it never started.
+pub fn acquire_run_slot(client: &Client) -> Result<RunSlot, ApiError> {
+    let policy = &client.policy;
+
+    with_usage(client, |usage, now| {
+        let window = usage.window;
+
+        if let Some(max) = policy.max_concurrent_runs() {
+            if usage.running_runs >= max {
+                return Err(too_many_requests(
+                    ApiErrorCode::ConcurrencyLimitExceeded,
+                    format!("At most {} runs can be executed at the same time", max),
+                    "max_concurrent_runs",
+                    max,
+                    usage.running_runs,
+                    None,
+                ));
+            }
+        }
+
+        if let Some(max) = policy.max_runs_per_window() {
+            if usage.runs.len() as u64 >= max {
+                return Err(too_many_requests(
+                    ApiErrorCode::QuotaExceeded,
+                    format!(
+                        "At most {} runs can be executed every {} seconds",
+                        max,
+                        window.as_secs_f64()
+                    ),
+                    "max_runs_per_window",
+                    max,
+                    usage.runs.len(),
+                    usage
+                        .runs
+                        .front()
+                        .map(|at| seconds_until_expiry(*at, window, now)),
+                ));
+            }
+        }
+
+        if let Some(max) = policy.max_cpu_time_per_window() {
+            let used = usage.total_cpu_time();
+
+            if used >= max {
+                return Err(too_many_requests(
+                    ApiErrorCode::QuotaExceeded,
+                    format!(
+                        "At most {} seconds of CPU time can be used every {} seconds",
+                        max,
+                        window.as_secs_f64()
+                    ),
+                    "max_cpu_time_per_window",
+                    max,
+                    used,
+                    usage.cpu_time_retry_after(max, now),
+                ));
+            }
+        }
+
+        usage.running_runs += 1;
+        usage.runs.push_back(now);
+
+        Ok(RunSlot {
+            client: client.clone(),
+            reserved_at: now,
+            started: false,
+        })
+    })
+}
+
+pub struct RunSlot {
+    client: Client,
+    reserved_at: Instant,
+    started: bool,
+}
+
+impl RunSlot {
+    /// Keeps the run in the client's quota, called once the run leaves the queue.
+    pub fn start(&mut self) {
+        self.started = true;
+    }
+
+    /// Counts the CPU time of a phase in the client's quota.
+    pub fn record_cpu_time(&self, seconds: f64) {
+        with_usage(&self.client, |usage, now| {
+            usage.cpu_time.push_back((now, seconds))
+        });
+    }
+}
+
+impl Drop for RunSlot {
+    fn drop(&mut self) {
+        let (reserved_at, started) = (self.reserved_at, self.started);
+
+        with_usage(&self.client, |usage, _| {
+            usage.running_runs = usage.running_runs.saturating_sub(1);
+
+            // Rejected, cancelled or disconnected before leaving the queue
+            if !started {
+                if let Some(i) = usage.runs.iter().position(|at| *at == reserved_at) {
+                    usage.runs.remove(i);
+                }
+            }
+        });
+    }
+}
+
+/// Current usage of a client, within its quota window
+pub struct UsageSnapshot {
+    pub remaining_requests: Option<f64>,
+    pub running_runs: usize,
+    pub runs: usize,
+    pub cpu_time: f64,
+}
+
+pub fn snapshot(client: &Client) -> UsageSnapshot {
+    let policy = &client.policy;
+
+    with_usage(client, |usage, now| UsageSnapshot {
+        remaining_requests: match (policy.requests_per_second(), policy.burst()) {
+            (Some(requests_per_second), Some(burst)) => {
+                Some(usage.refill(requests_per_second, burst, now))
+            }
+            _ => None,
+        },
+        running_runs: usage.running_runs,
+        runs: usage.runs.len(),
+        cpu_time: usage.total_cpu_time(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Policy;
+
+    fn usage(window: u64) -> ClientUsage {
+        ClientUsage {
+            window: Duration::from_secs(window),
+            ..ClientUsage::new()
+        }
+    }
+
+    fn client(name: &str, policy: Policy) -> Client {
+        Client {
+            name: Some(format!("usage-tests-{}", name)),
+            address: None,
+            policy,
+        }
+    }
+
+    fn retry_after(error: &ApiError) -> f64 {
+        error.details.as_ref().unwrap().retry_after.unwrap()
+    }
+
+    #[test]
+    fn bucket_starts_full_and_refills() {
+        let mut usage = usage(60);
+        let start = usage.refilled_at;
+
+        assert_eq!(usage.refill(2.0, 5.0, start), 5.0);
+
+        usage.tokens = Some(0.0);
+
+        assert_eq!(
+            usage.refill(2.0, 5.0, start + Duration::from_millis(500)),
+            1.0
+        );
+        assert_eq!(usage.refill(2.0, 5.0, start + Duration::from_secs(2)), 4.0);
+        // Never above the burst
+        assert_eq!(usage.refill(2.0, 5.0, start + Duration::from_secs(60)), 5.0);
+    }
+
+    #[test]
+    fn runs_and_cpu_time_expire_with_the_window() {
+        let mut usage = usage(60);
+        let start = Instant::now();
+
+        usage
+            .runs
+            .extend(vec![start, start + Duration::from_secs(30)]);
+        usage
+            .cpu_time
+            .extend(vec![(start, 1.5), (start + Duration::from_secs(30), 2.0)]);
+
+        usage.expire(start + Duration::from_secs(59));
+        assert_eq!(usage.runs.len(), 2);
+        assert_eq!(usage.total_cpu_time(), 3.5);
+
+        usage.expire(start + Duration::from_secs(60));
+        assert_eq!(usage.runs.len(), 1);
+        assert_eq!(usage.total_cpu_time(), 2.0);
+
+        usage.expire(start + Duration::from_secs(120));
+        assert!(usage.runs.is_empty());
+        assert_eq!(usage.total_cpu_time(), 0.0);
+        assert!(usage.total_cpu_time().is_sign_positive());
+    }
+
+    #[test]
+    fn cpu_time_retry_after_waits_for_enough_expired_time() {
+        let mut usage = usage(100);
+        let start = Instant::now();
+
+        usage.cpu_time.extend(vec![
+            (start, 4.0),
+            (start + Duration::from_secs(10), 4.0),
+            (start + Duration::from_secs(20), 4.0),
+        ]);
+
+        let now = start + Duration::from_secs(30);
+
+        // 12 seconds used, the first phase has to expire to get under 10
+        assert_eq!(usage.cpu_time_retry_after(10.0, now), Some(70.0));
+        // Under 5, the first two phases have to expire
+        assert_eq!(usage.cpu_time_retry_after(5.0, now), Some(80.0));
+        // 0 can't be reached before everything expired
+        assert_eq!(usage.cpu_time_retry_after(0.0, now), None);
+    }
+
+    #[test]
+    fn rate_limit_tells_when_to_retry() {
+        let client = client(
+            "rate-limit",
+            Policy {
+                requests_per_second: Some(0.5),
+                burst: Some(2.0),
+                ..Default::default()
+            },
+        );
+
+        assert!(check_rate_limit(&client).is_ok());
+        assert!(check_rate_limit(&client).is_ok());
+
+        let error = check_rate_limit(&client).unwrap_err();
+        let retry_after = retry_after(&error);
+
+        assert_eq!(error.code, ApiErrorCode::RateLimitExceeded);
+        assert!(retry_after > 1.9 && retry_after <= 2.0, "{}", retry_after);
+    }
+
+    #[test]
+    fn runs_quota_tells_when_to_retry() {
+        let client = client(
+            "runs-quota",
+            Policy {
+                quota_window: Some(60.0),
+                max_runs_per_window: Some(2),
+                ..Default::default()
+            },
+        );
+
+        acquire_run_slot(&client).unwrap().start();
+        acquire_run_slot(&client).unwrap().start();
+
+        let error = acquire_run_slot(&client).err().unwrap();
+        let retry_after = retry_after(&error);
+
+        assert_eq!(error.code, ApiErrorCode::QuotaExceeded);
+        assert!(retry_after > 59.0 && retry_after <= 60.0, "{}", retry_after);
+        assert_eq!(snapshot(&client).runs, 2);
+        assert_eq!(snapshot(&client).running_runs, 0);
+    }
+
+    #[test]
+    fn cpu_time_quota_counts_the_recorded_time() {
+        let client = client(
+            "cpu-time-quota",
+            Policy {
+                quota_window: Some(60.0),
+                max_cpu_time_per_window: Some(3.0),
+                ..Default::default()
+            },
+        );
+
+        let mut slot = acquire_run_slot(&client).unwrap();
+        slot.start();
+        slot.record_cpu_time(2.0);
+        slot.record_cpu_time(1.5);
+        drop(slot);
+
+        let error = acquire_run_slot(&client).err().unwrap();
+
+        assert_eq!(error.code, ApiErrorCode::QuotaExceeded);
+        assert!(retry_after(&error) > 59.0);
+        assert_eq!(snapshot(&client).cpu_time, 3.5);
+    }
+
+    #[test]
+    fn out_of_range_quota_window_never_expires() {
+        let client = client(
+            "huge-window",
+            Policy {
+                quota_window: Some(1e20),
+                max_runs_per_window: Some(1),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(client.policy.quota_window(), Duration::MAX);
+
+        acquire_run_slot(&client).unwrap().start();
+
+        let error = acquire_run_slot(&client).err().unwrap();
+
+        assert_eq!(error.code, ApiErrorCode::QuotaExceeded);
+        // The usage lock is still usable
+        assert_eq!(snapshot(&client).runs, 1);
+    }
+
+    #[test]
+    fn runs_that_never_started_leave_the_quota() {
+        let client = client(
+            "never-started",
+            Policy {
+                quota_window: Some(60.0),
+                max_runs_per_window: Some(1),
+                ..Default::default()
+            },
+        );
+
+        let slot = acquire_run_slot(&client).unwrap();
+
+        // Counted while queued
+        assert_eq!(snapshot(&client).runs, 1);
+        assert!(acquire_run_slot(&client).is_err());
+
+        drop(slot);
+        assert_eq!(snapshot(&client).runs, 0);
+
+        acquire_run_slot(&client).unwrap().start();
+        assert_eq!(snapshot(&client).runs, 1);
+        assert_eq!(snapshot(&client).running_runs, 0);
+    }
+}
@@ -0,0 +1,146 @@
+/// Container being read, along with the current key or index
+enum Frame {
+    Object(Option<String>),
+    Array(usize),
+}
+
+/// Byte offset of a 1-based line and column, as reported by `serde_json` errors.
+fn offset(json: &[u8], line: usize, column: usize) -> usize {
+    let line_start = match line {
+        0 | 1 => 0,
+        _ => json
+            .iter()
+            .enumerate()
+            .filter(|(_, byte)| **byte == b'\n')
+            .nth(line - 2)
+            .map_or(json.len(), |(i, _)| i + 1),
+    };
+
+    (line_start + column).min(json.len())
+}
+
+/// Offset following the string starting at `start`
+fn string_end(json: &[u8], start: usize) -> usize {
+    let mut i = start + 1;
+
+    while i < json.len() {
+        match json[i] {
+            b'\\' => i += 2,
+            b'"' => return i + 1,
+            _ => i += 1,
+        }
+    }
+
+    json.len()
+}
+
+/// Path of the value read at a line and column of a JSON document, e.g. `phases[2].sandbox_settings.memory_limit`.
+/// Empty for the root value.
+pub fn path_at(json: &[u8], line: usize, column: usize) -> String {
+    let end = offset(json, line, column);
+    let mut frames = vec![];
+    let mut i = 0;
+
+    while i < end {
+        match json[i] {
+            b'{' => frames.push(Frame::Object(None)),
+            b'[' => frames.push(Frame::Array(0)),
+            b'}' | b']' => {
+                frames.pop();
+            }
+            b',' => match frames.last_mut() {
+                Some(Frame::Object(key)) => *key = None,
+                Some(Frame::Array(index)) => *index += 1,
+                None => {}
+            },
+            b'"' => {
+                let string_end = string_end(json, i);
+
+                // Strings read while no key is set are keys, the others are values
+                if let Some(Frame::Object(key @ None)) = frames.last_mut() {
+                    *key = serde_json::from_slice(&json[i..string_end]).ok();
+                }
+
+                i = string_end;
+                continue;
+            }
+            _ => {}
+        }
+
+        i += 1;
+    }
+
+    let mut path = String::new();
+
+    for frame in frames {
+        match frame {
+            Frame::Object(Some(key)) if path.is_empty() => path.push_str(&key),
+            Frame::Object(Some(key)) => path.push_str(&format!(".{}", key)),
+            Frame::Object(None) => break,
+            Frame::Array(index) => path.push_str(&format!("[{}]", index)),
+        }
+    }
+
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Settings {
+        limit: Option<u64>,
+        names: Option<Vec<String>>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Body {
+        phases: Vec<HashMap<String, Settings>>,
+        count: Option<u64>,
+    }
+
+    fn error_path(json: &str) -> String {
+        let error = serde_json::from_str::<Body>(json).unwrap_err();
+
+        path_at(json.as_bytes(), error.line(), error.column())
+    }
+
+    #[test]
+    fn path_of_nested_values() {
+        assert_eq!(
+            error_path(r#"{"phases": [{}, {"a": {"limit": 1}, "b": {"limit": "x"}}]}"#),
+            "phases[1].b.limit"
+        );
+        assert_eq!(
+            error_path(r#"{"phases": [{"a": {"names": ["x", 2]}}]}"#),
+            "phases[0].a.names[1]"
+        );
+        assert_eq!(error_path(r#"{"phases": [], "count": -1}"#), "count");
+    }
+
+    #[test]
+    fn keys_and_strings_with_special_characters() {
+        assert_eq!(
+            error_path(r#"{"phases": [{"a \" , { [": {"names": ["}", "]\""], "limit": true}}]}"#),
+            r#"phases[0].a " , { [.limit"#
+        );
+    }
+
+    #[test]
+    fn path_across_lines() {
+        assert_eq!(
+            error_path("{\n  \"phases\": [\n    {\"a\": {\"limit\": 1}},\n    {\"a\": {\"limit\": 1.5}}\n  ]\n}"),
+            "phases[1].a.limit"
+        );
+    }
+
+    #[test]
+    fn root_for_missing_fields() {
+        assert_eq!(error_path(r#"{"count": 1}"#), "");
+    }
+}
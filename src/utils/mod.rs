@@ -1 +1,4 @@
+pub mod json_path;
 pub mod parsed_env;
+pub mod units;
+pub mod validators;
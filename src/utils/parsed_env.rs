@@ -1,26 +1,56 @@
 use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
 
-pub fn get<S>(name: S, default: u64) -> u64
+pub fn get<S, T>(name: S, default: T) -> T
 where
     S: Into<String>,
+    T: FromStr,
+    T::Err: Display,
+{
+    get_optional(name).unwrap_or(default)
+}
+
+/// Same as [`get`] but without a default: unset variables (or `-1`) give `None`.
+pub fn get_optional<S, T>(name: S) -> Option<T>
+where
+    S: Into<String>,
+    T: FromStr,
+    T::Err: Display,
 {
     let name_string = name.into();
 
     match env::var(name_string.clone()) {
         Ok(value) => match value.as_str() {
-            "-1" => default,
+            "-1" => None,
             _ => match value.parse() {
-                Ok(max) => max,
+                Ok(max) => Some(max),
                 Err(e) => {
-                    eprintln!(
-                        "Failed to parse environment variable '{}' as an `u64`: {}",
-                        name_string, e
+                    tracing::warn!(
+                        "Failed to parse environment variable '{}' as an `{}`: {}",
+                        name_string,
+                        std::any::type_name::<T>(),
+                        e
                     );
 
-                    default
+                    None
                 }
             },
         },
+        Err(_) => None,
+    }
+}
+
+/// Reads a boolean flag, `true` and `yes` (case insensitive) are truthy.
+pub fn get_bool<S>(name: S, default: bool) -> bool
+where
+    S: Into<String>,
+{
+    match env::var(name.into()) {
+        Ok(value) => {
+            let lowercase_value = value.to_lowercase();
+            ["true", "yes"].iter().any(|&s| s == lowercase_value)
+        }
         Err(_) => default,
     }
 }
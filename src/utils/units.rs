@@ -0,0 +1,278 @@
+use serde::de::{self, Deserializer, Visitor};
+use std::fmt;
+use std::marker::PhantomData;
+
+enum NumberOrString<'a> {
+    Number(f64),
+    String(&'a str),
+}
+
+/// Splits a value such as `"2.5s"` into its numeric part and its (lowercased) unit.
+fn split_unit(value: &str) -> Result<(f64, String), String> {
+    let value = value.trim();
+    // The unit is the trailing letters, the number can have an exponent (`1e3`)
+    let unit_start = value
+        .rfind(|c: char| !c.is_ascii_alphabetic())
+        .map_or(0, |i| i + 1);
+
+    let (number, unit) = value.split_at(unit_start);
+
+    let number = number
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| format!("invalid value '{}'", value))?;
+
+    if !number.is_finite() || number < 0.0 {
+        return Err(format!("invalid value '{}'", value));
+    }
+
+    Ok((number, unit.trim().to_lowercase()))
+}
+
+/// Parses a duration into seconds.
+/// Accepts a bare number of seconds (`2.5`) or a number followed by `ms`, `s`, `m` or `h`.
+pub fn parse_seconds(value: &str) -> Result<f64, String> {
+    let (number, unit) = split_unit(value)?;
+
+    let multiplier = match unit.as_str() {
+        "ms" => 0.001,
+        "" | "s" | "sec" => 1.0,
+        "m" | "min" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("unknown time unit '{}' in '{}'", unit, value)),
+    };
+
+    Ok(number * multiplier)
+}
+
+/// Parses a size into kilobytes (1 KB = 1024 bytes, like isolate does).
+/// Accepts a bare number of kilobytes (`512000`) or a number followed by a unit (`256MB`, `1GiB`).
+/// Every unit is a power of 1024, `MB` and `MiB` are therefore the same.
+pub fn parse_kilobytes(value: &str) -> Result<u64, String> {
+    let (number, unit) = split_unit(value)?;
+
+    let bytes_multiplier: f64 = match unit.as_str() {
+        "b" => 1.0,
+        "" | "k" | "kb" | "kib" => 1024.0,
+        "m" | "mb" | "mib" => 1024.0 * 1024.0,
+        "g" | "gb" | "gib" => 1024.0 * 1024.0 * 1024.0,
+        _ => return Err(format!("unknown size unit '{}' in '{}'", unit, value)),
+    };
+
+    let kilobytes = (number * bytes_multiplier / 1024.0).ceil();
+
+    if kilobytes > u64::MAX as f64 {
+        return Err(format!("value '{}' is too large", value));
+    }
+
+    Ok(kilobytes as u64)
+}
+
+fn seconds(value: NumberOrString) -> Result<f64, String> {
+    match value {
+        NumberOrString::Number(value) if value.is_finite() && value >= 0.0 => Ok(value),
+        NumberOrString::Number(value) => Err(format!("invalid value '{}'", value)),
+        NumberOrString::String(value) => parse_seconds(value),
+    }
+}
+
+fn kilobytes(value: NumberOrString) -> Result<u64, String> {
+    match value {
+        NumberOrString::Number(value) => parse_kilobytes(&value.to_string()),
+        NumberOrString::String(value) => parse_kilobytes(value),
+    }
+}
+
+/// Parses optional numbers and strings while they are visited, so that `serde_json` errors point
+/// at the invalid value rather than at the end of the enclosing object.
+struct UnitVisitor<T> {
+    expecting: &'static str,
+    parse: fn(NumberOrString) -> Result<T, String>,
+    value: PhantomData<T>,
+}
+
+impl<T> UnitVisitor<T> {
+    fn new(expecting: &'static str, parse: fn(NumberOrString) -> Result<T, String>) -> Self {
+        UnitVisitor {
+            expecting,
+            parse,
+            value: PhantomData,
+        }
+    }
+}
+
+impl<'de, T> Visitor<'de> for UnitVisitor<T> {
+    type Value = Option<T>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(self.expecting)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Option<T>, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Option<T>, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Option<T>, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Option<T>, E> {
+        (self.parse)(NumberOrString::Number(value))
+            .map(Some)
+            .map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Option<T>, E> {
+        self.visit_f64(value as f64)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Option<T>, E> {
+        self.visit_f64(value as f64)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Option<T>, E> {
+        (self.parse)(NumberOrString::String(value))
+            .map(Some)
+            .map_err(E::custom)
+    }
+}
+
+/// `deserialize_with` helper for optional durations, see [`parse_seconds`].
+pub fn deserialize_seconds<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_option(UnitVisitor::new(
+        "a number of seconds or a duration such as \"500ms\"",
+        seconds,
+    ))
+}
+
+/// `deserialize_with` helper for optional sizes, see [`parse_kilobytes`].
+pub fn deserialize_kilobytes<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_option(UnitVisitor::new(
+        "a number of kilobytes or a size such as \"256MB\"",
+        kilobytes,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Limits {
+        #[serde(default, deserialize_with = "deserialize_seconds")]
+        time: Option<f64>,
+        #[serde(default, deserialize_with = "deserialize_kilobytes")]
+        memory: Option<u64>,
+    }
+
+    fn limits(json: &str) -> Result<Limits, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn seconds_with_units() {
+        assert_eq!(parse_seconds("500ms"), Ok(0.5));
+        assert_eq!(parse_seconds("2.5s"), Ok(2.5));
+        assert_eq!(parse_seconds("2.5"), Ok(2.5));
+        assert_eq!(parse_seconds(" 3 sec "), Ok(3.0));
+        assert_eq!(parse_seconds("10m"), Ok(600.0));
+        assert_eq!(parse_seconds("1h"), Ok(3600.0));
+        assert_eq!(parse_seconds("1e3"), Ok(1000.0));
+        assert_eq!(parse_seconds("1.5e2ms"), Ok(0.15));
+    }
+
+    #[test]
+    fn invalid_seconds() {
+        assert_eq!(parse_seconds("-1s"), Err("invalid value '-1s'".into()));
+        assert_eq!(parse_seconds("-0.5"), Err("invalid value '-0.5'".into()));
+        assert_eq!(parse_seconds("inf"), Err("invalid value 'inf'".into()));
+        assert_eq!(parse_seconds("s"), Err("invalid value 's'".into()));
+        assert_eq!(parse_seconds(""), Err("invalid value ''".into()));
+        assert_eq!(
+            parse_seconds("2 days"),
+            Err("unknown time unit 'days' in '2 days'".into())
+        );
+    }
+
+    #[test]
+    fn kilobytes_with_units() {
+        assert_eq!(parse_kilobytes("512000"), Ok(512000));
+        assert_eq!(parse_kilobytes("64KB"), Ok(64));
+        assert_eq!(parse_kilobytes("256MB"), Ok(262144));
+        assert_eq!(parse_kilobytes("256mib"), Ok(262144));
+        assert_eq!(parse_kilobytes("1GiB"), Ok(1048576));
+        assert_eq!(parse_kilobytes("1.5 G"), Ok(1572864));
+        assert_eq!(parse_kilobytes("1e3"), Ok(1000));
+        assert_eq!(parse_kilobytes("2048B"), Ok(2));
+    }
+
+    #[test]
+    fn kilobytes_round_up() {
+        assert_eq!(parse_kilobytes("512B"), Ok(1));
+        assert_eq!(parse_kilobytes("1025B"), Ok(2));
+        assert_eq!(parse_kilobytes("0.1"), Ok(1));
+        assert_eq!(parse_kilobytes("0B"), Ok(0));
+    }
+
+    #[test]
+    fn invalid_kilobytes() {
+        assert_eq!(parse_kilobytes("-1MB"), Err("invalid value '-1MB'".into()));
+        assert_eq!(
+            parse_kilobytes("12parsecs"),
+            Err("unknown size unit 'parsecs' in '12parsecs'".into())
+        );
+        assert_eq!(
+            parse_kilobytes("1TB"),
+            Err("unknown size unit 'tb' in '1TB'".into())
+        );
+        assert_eq!(
+            parse_kilobytes("1e300GB"),
+            Err("value '1e300GB' is too large".into())
+        );
+    }
+
+    #[test]
+    fn deserialize_numbers_and_strings() {
+        let parsed = limits(r#"{"time": "250ms", "memory": "1MB"}"#).unwrap();
+
+        assert_eq!(parsed.time, Some(0.25));
+        assert_eq!(parsed.memory, Some(1024));
+
+        let parsed = limits(r#"{"time": 2, "memory": 512000}"#).unwrap();
+
+        assert_eq!(parsed.time, Some(2.0));
+        assert_eq!(parsed.memory, Some(512000));
+
+        let parsed = limits(r#"{"time": null}"#).unwrap();
+
+        assert_eq!(parsed.time, None);
+        assert_eq!(parsed.memory, None);
+    }
+
+    #[test]
+    fn deserialize_fractional_kilobytes() {
+        assert_eq!(limits(r#"{"memory": 1.5}"#).unwrap().memory, Some(2));
+        assert_eq!(limits(r#"{"memory": 0.001}"#).unwrap().memory, Some(1));
+        assert_eq!(limits(r#"{"memory": 1e3}"#).unwrap().memory, Some(1000));
+        assert_eq!(limits(r#"{"memory": 2.0}"#).unwrap().memory, Some(2));
+    }
+
+    #[test]
+    fn deserialize_invalid_values() {
+        assert!(limits(r#"{"memory": -1}"#).is_err());
+        assert!(limits(r#"{"time": -0.5}"#).is_err());
+        assert!(limits(r#"{"time": true}"#).is_err());
+        assert!(limits(r#"{"memory": "1 parsec"}"#).is_err());
+    }
+}
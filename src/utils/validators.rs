@@ -0,0 +1,314 @@
+use crate::runner::phase_settings::{PhaseEnvironment, PhaseSettings};
+use crate::utils;
+use serde::Serialize;
+use std::borrow::Cow;
+use validator::ValidationError;
+
+/// Builds a validation error. `actual` is reported instead of the (possibly huge) validated value.
+fn error<T: Serialize>(code: &'static str, message: String, actual: &T) -> ValidationError {
+    let mut error = ValidationError::new(code);
+    error.message = Some(Cow::from(message));
+    error.add_param(Cow::from("actual"), actual);
+
+    error
+}
+
+fn max_error(size: usize, max: usize, unit: &str) -> ValidationError {
+    let mut error = error(
+        "limit_exceeded",
+        format!("maximum allowed size is {} {}", max, unit),
+        &size,
+    );
+    error.add_param(Cow::from("max"), &max);
+
+    error
+}
+
+pub fn validate_phase_count(phases: &[PhaseSettings]) -> Result<(), ValidationError> {
+    let max = utils::parsed_env::get("MAX_PHASES", 16usize);
+
+    if phases.is_empty() {
+        let mut error = error(
+            "invalid_request",
+            "at least one phase is required".into(),
+            &0,
+        );
+        error.add_param(Cow::from("min"), &1);
+
+        return Err(error);
+    }
+
+    if phases.len() > max {
+        return Err(max_error(phases.len(), max, "phases"));
+    }
+
+    Ok(())
+}
+
+pub fn validate_script_size(script: &str) -> Result<(), ValidationError> {
+    let max = utils::parsed_env::get("MAX_SCRIPT_SIZE", 65536usize);
+
+    match script.len() > max {
+        true => Err(max_error(script.len(), max, "bytes")),
+        false => Ok(()),
+    }
+}
+
+pub fn validate_argv_size(argv: &[String]) -> Result<(), ValidationError> {
+    let max = utils::parsed_env::get("MAX_SCRIPT_SIZE", 65536usize);
+    let size = argv.iter().map(|arg| arg.len()).sum();
+
+    if let Some(arg) = argv.iter().find(|arg| arg.contains('\0')) {
+        return Err(error(
+            "invalid_request",
+            "arguments must not contain NUL characters".into(),
+            arg,
+        ));
+    }
+
+    match size > max {
+        true => Err(max_error(size, max, "bytes")),
+        false => Ok(()),
+    }
+}
+
+/// Longer paths are rejected by the kernel anyway
+const MAX_CWD_LENGTH: usize = 4095;
+
+pub fn validate_cwd(cwd: &str) -> Result<(), ValidationError> {
+    if cwd.len() > MAX_CWD_LENGTH {
+        return Err(max_error(cwd.len(), MAX_CWD_LENGTH, "bytes"));
+    }
+
+    match cwd.contains('\0') {
+        true => Err(error(
+            "invalid_request",
+            "must not contain NUL characters".into(),
+            &cwd,
+        )),
+        false => Ok(()),
+    }
+}
+
+pub fn validate_stdin_size(stdin: &str) -> Result<(), ValidationError> {
+    let max = utils::parsed_env::get("MAX_STDIN_SIZE", 65536usize);
+
+    match stdin.len() > max {
+        true => Err(max_error(stdin.len(), max, "bytes")),
+        false => Ok(()),
+    }
+}
+
+pub fn validate_name(name: &str) -> Result<(), ValidationError> {
+    let max = utils::parsed_env::get("MAX_NAME_LENGTH", 64usize);
+    let length = name.chars().count();
+
+    if length > max {
+        return Err(max_error(length, max, "characters"));
+    }
+
+    if name.chars().any(char::is_control) {
+        return Err(error(
+            "invalid_request",
+            "must not contain control characters".into(),
+            &name,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Environment variable names follow the POSIX shell syntax: `[A-Za-z_][A-Za-z0-9_]*`,
+/// which also excludes `=` and NUL characters.
+pub fn is_valid_environment_key(key: &str) -> bool {
+    let mut chars = key.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Every problem of an environment map, along with the variable it concerns (`None` for the
+/// whole map), so that each invalid variable is reported.
+pub fn environment_errors(environment: &PhaseEnvironment) -> Vec<(Option<&str>, ValidationError)> {
+    let max_count = utils::parsed_env::get("MAX_ENVIRONMENT_VARIABLES", 64usize);
+    let max_size = utils::parsed_env::get("MAX_ENVIRONMENT_SIZE", 16384usize);
+
+    let mut errors = vec![];
+
+    if environment.len() > max_count {
+        errors.push((None, max_error(environment.len(), max_count, "variables")));
+    }
+
+    let size = environment
+        .iter()
+        .map(|(key, value)| key.len() + value.as_ref().map_or(0, String::len))
+        .sum();
+
+    if size > max_size {
+        errors.push((None, max_error(size, max_size, "bytes")));
+    }
+
+    let mut variables = environment.iter().collect::<Vec<_>>();
+    variables.sort();
+
+    for (key, value) in variables {
+        if !is_valid_environment_key(key) {
+            errors.push((
+                Some(key.as_str()),
+                error(
+                    "invalid_request",
+                    "invalid variable name, names must match [A-Za-z_][A-Za-z0-9_]*".into(),
+                    key,
+                ),
+            ));
+        } else if value.as_deref().is_some_and(|value| value.contains('\0')) {
+            errors.push((
+                Some(key.as_str()),
+                error(
+                    "invalid_request",
+                    "must not contain NUL characters".into(),
+                    key,
+                ),
+            ));
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code<T>(result: Result<T, ValidationError>) -> Option<String> {
+        result.err().map(|error| error.code.to_string())
+    }
+
+    #[test]
+    fn environment_keys() {
+        for key in ["A", "_", "_A1", "PATH", "a_b_C_9"] {
+            assert!(is_valid_environment_key(key), "{}", key);
+        }
+
+        for key in ["", "1A", "A-B", "A B", "A=B", "A\0", "É", "A.B", " A"] {
+            assert!(!is_valid_environment_key(key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn phase_count() {
+        let phase: PhaseSettings = serde_json::from_str(r#"{"script": "true"}"#).unwrap();
+        let phases = |count| vec![phase.clone(); count];
+
+        assert_eq!(
+            code(validate_phase_count(&phases(0))),
+            Some("invalid_request".into())
+        );
+        assert_eq!(code(validate_phase_count(&phases(1))), None);
+        assert_eq!(code(validate_phase_count(&phases(16))), None);
+        assert_eq!(
+            code(validate_phase_count(&phases(17))),
+            Some("limit_exceeded".into())
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        let max = 65536;
+
+        assert_eq!(code(validate_script_size(&"x".repeat(max))), None);
+        assert_eq!(
+            code(validate_script_size(&"x".repeat(max + 1))),
+            Some("limit_exceeded".into())
+        );
+        assert_eq!(code(validate_stdin_size(&"x".repeat(max))), None);
+        assert_eq!(
+            code(validate_stdin_size(&"x".repeat(max + 1))),
+            Some("limit_exceeded".into())
+        );
+
+        // The arguments share the script's limit
+        let argv = vec!["x".repeat(max / 2), "x".repeat(max / 2)];
+        assert_eq!(code(validate_argv_size(&argv)), None);
+
+        let argv = vec!["x".repeat(max / 2), "x".repeat(max / 2 + 1)];
+        let error = validate_argv_size(&argv).unwrap_err();
+        assert_eq!(error.code, "limit_exceeded");
+        assert_eq!(error.params["max"], max);
+        assert_eq!(error.params["actual"], max + 1);
+
+        assert_eq!(
+            code(validate_argv_size(&["/bin/echo".into(), "a\0b".into()])),
+            Some("invalid_request".into())
+        );
+    }
+
+    #[test]
+    fn paths_and_names() {
+        assert_eq!(code(validate_cwd("/box/src")), None);
+        assert_eq!(code(validate_cwd("src")), None);
+        assert_eq!(code(validate_cwd(&"a".repeat(MAX_CWD_LENGTH))), None);
+        assert_eq!(
+            code(validate_cwd(&"a".repeat(MAX_CWD_LENGTH + 1))),
+            Some("limit_exceeded".into())
+        );
+        assert_eq!(code(validate_cwd("/box\0")), Some("invalid_request".into()));
+
+        assert_eq!(code(validate_name("compile step")), None);
+        // Counted in characters rather than bytes
+        assert_eq!(code(validate_name(&"é".repeat(64))), None);
+        assert_eq!(
+            code(validate_name(&"é".repeat(65))),
+            Some("limit_exceeded".into())
+        );
+        assert_eq!(code(validate_name("a\nb")), Some("invalid_request".into()));
+        assert_eq!(code(validate_name("a\tb")), Some("invalid_request".into()));
+    }
+
+    #[test]
+    fn environment_caps() {
+        let environment = |count: usize, value_size: usize| {
+            (0..count)
+                .map(|i| (format!("V{:03}", i), Some("x".repeat(value_size))))
+                .collect::<PhaseEnvironment>()
+        };
+        let codes = |environment: &PhaseEnvironment| {
+            environment_errors(environment)
+                .into_iter()
+                .map(|(key, error)| (key.map(String::from), error.code.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert!(codes(&environment(64, 1)).is_empty());
+        assert_eq!(
+            codes(&environment(65, 1)),
+            vec![(None, "limit_exceeded".into())]
+        );
+
+        // 4 bytes of name and 252 of value per variable
+        assert!(codes(&environment(64, 252)).is_empty());
+        assert_eq!(
+            codes(&environment(64, 253)),
+            vec![(None, "limit_exceeded".into())]
+        );
+
+        // Both caps and every invalid variable are reported, unset variables included
+        let mut environment = environment(65, 253);
+        environment.insert("1A".into(), None);
+        environment.insert("B".into(), Some("\0".into()));
+
+        assert_eq!(
+            codes(&environment),
+            vec![
+                (None, "limit_exceeded".into()),
+                (None, "limit_exceeded".into()),
+                (Some("1A".into()), "invalid_request".into()),
+                (Some("B".into()), "invalid_request".into()),
+            ]
+        );
+    }
+}